@@ -1,79 +1,183 @@
-use common::client::CommandManager;
-use common::command::{ self, RegisterCommand, RegisterResponseCommand };
-use tokio::net::TcpStream;
-use tokio_native_tls::{ native_tls, TlsConnector };
-use native_tls::{ Certificate, TlsConnector as NativeTlsConnector };
-use std::env;
-use std::fs::File;
-use std::io::Read;
-use std::net::ToSocketAddrs;
+use clap::{ Parser, Subcommand };
+use common::client::{ self, CommandManager };
+use common::command::{
+    self,
+    ErrorCommand,
+    HelloCommand,
+    LoginCommand,
+    RegisterCommand,
+    RegisterResponseCommand,
+};
+use common::envelope::Envelope;
+use common::transport::{ Connector, Endpoint };
+use common::trust::{ self, Pin, TrustConfig };
+use tokio_native_tls::native_tls::Identity;
+use std::fs;
+use std::path::PathBuf;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    if env::args().len() < 6 {
-        eprintln!("Usage: client_cli host port cert_file_path username password");
-        std::process::exit(1);
-    }
-
-    let host = &env::args().nth(1).unwrap();
-    let port = &env::args().nth(2).unwrap();
-    let cert_file_path = &env::args().nth(3).unwrap();
-    let username = &env::args().nth(4).unwrap();
-    let password = &env::args().nth(5).unwrap();
-
-    println!("Chargement du certificat du serveur");
-    let mut cert_file = File::open(cert_file_path)?;
-    let mut cert_buffer = Vec::new();
-    cert_file.read_to_end(&mut cert_buffer)?;
-    let cert = Certificate::from_pem(&cert_buffer)?;
+/// Talk to a termplay server from the command line
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// Server to connect to: tls://host:port, tcp://host:port or unix:///path
+    endpoint: Endpoint,
+    #[command(subcommand)]
+    request: Request,
+    /// PEM CA certificates to trust on top of the system ones, e.g. the
+    /// certificate of a self-signed server
+    #[arg(long, visible_alias = "cert")]
+    ca_file: Option<PathBuf>,
+    /// Only accept a server whose public key has this SHA-256 pin
+    /// (sha256//<base64>), may be repeated
+    #[arg(long = "pin")]
+    pins: Vec<Pin>,
+    /// Trust the server key on first use and remember it in this file,
+    /// instead of verifying its certificate
+    #[arg(long, conflicts_with_all = ["ca_file", "pins"])]
+    known_hosts: Option<PathBuf>,
+    /// Trust on first use with the default known hosts file
+    #[arg(long, conflicts_with_all = ["known_hosts", "ca_file", "pins"])]
+    tofu: bool,
+    /// Allow tcp:// endpoints, for local development only
+    #[arg(long)]
+    allow_plaintext: bool,
+    /// PEM client certificate, for servers that authenticate accounts by certificate
+    #[arg(long, requires = "client_key")]
+    client_cert: Option<PathBuf>,
+    /// PEM PKCS#8 private key of the client certificate
+    #[arg(long, requires = "client_cert")]
+    client_key: Option<PathBuf>,
+}
 
-    println!("Configuration du connecteur TLS avec le certificat du serveur");
-    let mut tls_connector = NativeTlsConnector::builder();
-    tls_connector.add_root_certificate(cert);
-    tls_connector.danger_accept_invalid_certs(true);
+#[derive(Subcommand)]
+enum Request {
+    /// Register a new account
+    Register {
+        username: String,
+        password: String,
+        /// Address the confirmation email is sent to
+        email: String,
+        /// Language of the confirmation email, taken from LANG when left out
+        #[arg(long)]
+        locale: Option<String>,
+    },
+    /// Open a session, with the client certificate when no credentials are given
+    Login {
+        #[arg(requires = "password")]
+        username: Option<String>,
+        password: Option<String>,
+    },
+}
 
-    let full = format!("{}:{}", host, port);
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
 
-    println!("Résolution de l'adresse du serveur");
-    let addrs = full.to_socket_addrs();
+    println!("Configuration du connecteur TLS");
+    let known_hosts = match args.tofu {
+        true => Some(trust::default_known_hosts_path().ok_or("Dossier de configuration introuvable")?),
+        false => args.known_hosts,
+    };
+    let trust = TrustConfig {
+        ca_file: args.ca_file,
+        pins: args.pins,
+        known_hosts,
+    };
+    let identity = match (&args.client_cert, &args.client_key) {
+        (Some(client_cert), Some(client_key)) => {
+            println!("Chargement du certificat client");
+            Some(Identity::from_pkcs8(&fs::read(client_cert)?, &fs::read(client_key)?)?)
+        }
+        _ => None,
+    };
+    let connector = Connector::new(trust, identity)?.allow_plaintext(args.allow_plaintext);
 
-    let first_addr = match addrs {
-        Ok(addr) => addr.collect::<Vec<_>>()[0],
+    println!("Connexion au serveur {}", args.endpoint);
+    let stream = match connector.connect(&args.endpoint).await {
+        Ok(stream) => stream,
         Err(e) => {
-            eprintln!("Erreur lors de la résolution de l'adresse : {}", e);
+            eprintln!("Erreur lors de la connexion au serveur : {}", e);
             std::process::exit(1);
         }
     };
-
-    println!("Connexion au serveur avec SSL");
-    let tls_connector = TlsConnector::from(tls_connector.build()?);
-    println!("Connexion au serveur {} sur le port {}", first_addr.ip(), port);
-    let stream = TcpStream::connect(format!("{}:{}", first_addr.ip(), port)).await?;
     println!("Connexion établie avec succès");
-    println!(
-        "Connexion sécurisée au serveur {} (ip: {}) sur le port {}",
-        host,
-        first_addr.ip(),
-        port
-    );
-    let stream = tls_connector.connect(host, stream).await?;
-    println!("Connexion sécurisée établie avec succès");
 
-    println!("Envoi du nom d'utilisateur et du mot de passe au serveur");
-    let stream = tokio::io::BufStream::new(stream);
     let mut cmd_manager = CommandManager::new(stream);
-    cmd_manager.send(
-        &command::UserCommand::Register(RegisterCommand {
-            login: username.clone(),
-            password: password.clone(),
-        })
-    ).await?;
 
-    println!("Attente d'une réponse du serveur");
-    match cmd_manager.receive::<RegisterResponseCommand>().await {
-        Ok(cmd) => {
-            println!("Réponse du serveur : {:?}", cmd);
+    println!("Négociation du protocole avec le serveur");
+    let hello = HelloCommand::new(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    match client::handshake(&mut cmd_manager, hello).await {
+        Ok(welcome) => {
+            println!(
+                "Connecté à {} {} (protocole {})",
+                welcome.server_name,
+                welcome.server_version,
+                welcome.protocol_version
+            );
         }
+        Err(e) => {
+            eprintln!("Erreur lors de la négociation : {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    let request = match args.request {
+        Request::Register { username, password, email, locale } => {
+            println!("Envoi du nom d'utilisateur, du mot de passe et de l'email au serveur");
+            command::UserCommand::Register(RegisterCommand {
+                login: username,
+                email,
+                password,
+                locale: locale.or_else(RegisterCommand::system_locale),
+            })
+        }
+        Request::Login { username: Some(username), password: Some(password) } => {
+            println!("Connexion avec le nom d'utilisateur et le mot de passe");
+            command::UserCommand::Login(LoginCommand {
+                login: username,
+                password,
+            })
+        }
+        Request::Login { .. } => {
+            println!("Connexion avec le certificat client");
+            command::UserCommand::CertificateLogin
+        }
+    };
+    cmd_manager.send(&Envelope::request(1, request)).await?;
+
+    println!("Attente d'une réponse du serveur");
+    match cmd_manager.receive().await {
+        Ok(reply) =>
+            match reply.command {
+                command::ServerCommand::Error(ErrorCommand { code, message, retryable }) => {
+                    eprintln!("Le serveur a refusé la demande ({:?}) : {}", code, message);
+                    if retryable {
+                        eprintln!("Le problème est temporaire, réessayez plus tard");
+                    }
+                    std::process::exit(1);
+                }
+                command::ServerCommand::RegisterResponse(RegisterResponseCommand {
+                    rejection: Some(rejection),
+                    ..
+                }) => {
+                    eprintln!("Inscription refusée ({:?}) : {}", rejection, rejection.message());
+                    std::process::exit(1);
+                }
+                command::ServerCommand::RegisterResponse(_) => {
+                    println!("Inscription enregistrée, l'email de confirmation est en cours d'envoi");
+                }
+                command::ServerCommand::LoginResponse(response) => {
+                    println!(
+                        "Connecté en tant que {}, session {} valable jusqu'à {} (secondes Unix)",
+                        response.profile.login,
+                        response.session_token,
+                        response.expires_at
+                    );
+                }
+                command => {
+                    println!("Réponse du serveur : {:?}", command);
+                }
+            }
         Err(e) => {
             eprintln!("Erreur lors de la réception de la réponse du serveur : {}", e);
             std::process::exit(1);
@@ -82,3 +186,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(options: &[&str]) -> Result<Args, clap::Error> {
+        let mut arguments = vec!["termplay-client-cli", "play.example.com:8080"];
+        arguments.extend_from_slice(options);
+        arguments.extend_from_slice(&["login", "alice", "secret"]);
+        Args::try_parse_from(arguments)
+    }
+
+    #[test]
+    fn trust_on_first_use_excludes_other_trust_options() {
+        let pin = format!("sha256//{}=", "A".repeat(43));
+
+        assert!(parse(&["--tofu"]).is_ok());
+        assert!(parse(&["--known-hosts", "known_hosts"]).is_ok());
+        assert!(parse(&["--tofu", "--ca-file", "ca.pem"]).is_err());
+        assert!(parse(&["--tofu", "--pin", &pin]).is_err());
+        assert!(parse(&["--tofu", "--known-hosts", "known_hosts"]).is_err());
+        assert!(parse(&["--known-hosts", "known_hosts", "--cert", "ca.pem"]).is_err());
+        assert!(parse(&["--known-hosts", "known_hosts", "--pin", &pin]).is_err());
+    }
+}
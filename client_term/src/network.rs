@@ -1,40 +1,80 @@
-use tokio::net::TcpStream;
+use common::client::{ self, CommandManager };
+use common::codec::Codec;
+use common::command::HelloCommand;
+use common::connection::ConnectionConfig;
+use common::transport::{ BoxedStream, Connector, Endpoint };
+use common::trust::{ self, TrustConfig };
 
-use tokio_native_tls::native_tls::TlsConnector;
-use std::net::ToSocketAddrs;
-use std::pin::Pin;
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
 
-pub type SSLStream = tokio_native_tls::TlsStream<TcpStream>;
+pub type ServerHandle = CommandManager<BoxedStream>;
 
-type ServerHandle = Pin<Box<SSLStream>>;
+/// Ping often enough for the displayed latency to stay meaningful
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
 
-// pub type BoxedStream<Item> = Pin<Box<dyn SSLStream<Item = Item> + Send>>;
+/// Server used when `TERMPLAY_SERVER` is not set
+const DEFAULT_SERVER: &str = "tls://localhost:8080";
 
-// pub type EventStream = BoxedStream<anyhow::Result<event::Event>>;
-
-// impl CommandWriter {
-//     pub fn new(writer: SSLStream) -> Self {
-//         Self { socket: writer }
-//     }
-
-//     pub async fn write(&mut self, command: &command::UserCommand) -> anyhow::Result<()> {
-//         let mut serialized_bytes = serde_json::to_vec(command)?;
-//         serialized_bytes.extend_from_slice(NEW_LINE);
-
-//         self.socket.write_all(serialized_bytes.as_slice()).await?;
+/// Read the server endpoint from `TERMPLAY_SERVER`. Plain `tcp://` endpoints
+/// also need `TERMPLAY_ALLOW_PLAINTEXT=1`.
+fn server_endpoint() -> anyhow::Result<(Endpoint, bool)> {
+    let endpoint = env::var("TERMPLAY_SERVER")
+        .unwrap_or_else(|_| DEFAULT_SERVER.to_string())
+        .parse()?;
+    let allow_plaintext = matches!(env::var("TERMPLAY_ALLOW_PLAINTEXT").as_deref(), Ok("1" | "true"));
+    Ok((endpoint, allow_plaintext))
+}
 
-//         Ok(())
-//     }
-// }
+/// How to trust the server certificate:
+/// - `TERMPLAY_CA_FILE`: PEM CA certificates to trust, e.g. a self-signed server certificate
+/// - `TERMPLAY_PINS`: comma separated `sha256//<base64>` public key pins
+/// - `TERMPLAY_KNOWN_HOSTS`: trust on first use, remembering keys in this file,
+///   or in the default one when set to `1`. Replaces certificate verification,
+///   so it cannot be combined with the other two.
+fn trust_config() -> anyhow::Result<TrustConfig> {
+    let ca_file = env::var_os("TERMPLAY_CA_FILE").map(PathBuf::from);
+    let pins = match env::var("TERMPLAY_PINS") {
+        Ok(pins) =>
+            pins
+                .split(',')
+                .map(str::trim)
+                .filter(|pin| !pin.is_empty())
+                .map(str::parse)
+                .collect::<anyhow::Result<_>>()?,
+        Err(_) => Vec::new(),
+    };
+    let known_hosts = match env::var_os("TERMPLAY_KNOWN_HOSTS") {
+        Some(path) if path == "1" => {
+            Some(
+                trust
+                    ::default_known_hosts_path()
+                    .ok_or_else(|| anyhow::anyhow!("No configuration directory for known hosts"))?
+            )
+        }
+        Some(path) => Some(PathBuf::from(path)),
+        None => None,
+    };
+    if known_hosts.is_some() && (ca_file.is_some() || !pins.is_empty()) {
+        anyhow::bail!("TERMPLAY_KNOWN_HOSTS cannot be combined with TERMPLAY_CA_FILE or TERMPLAY_PINS");
+    }
+    Ok(TrustConfig { ca_file, pins, known_hosts })
+}
 
 pub async fn create_server_handle() -> anyhow::Result<ServerHandle> {
-    let addr = "localhost:8080".to_socket_addrs()?.next().unwrap();
+    let (endpoint, allow_plaintext) = server_endpoint()?;
 
-    let socket = TcpStream::connect(&addr).await?;
-    let cx = TlsConnector::builder().build()?;
-    let cx = tokio_native_tls::TlsConnector::from(cx);
+    let connector = Connector::new(trust_config()?, None)?.allow_plaintext(allow_plaintext);
+    let stream = connector.connect(&endpoint).await?;
 
-    let socket = cx.connect("localhost", socket).await?;
+    let mut server_handle = CommandManager::with_config(stream, ConnectionConfig {
+        keepalive_interval: Some(KEEPALIVE_INTERVAL),
+        ..Default::default()
+    });
+    let mut hello = HelloCommand::new(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    hello.capabilities.push(Codec::MessagePack.capability().to_string());
+    client::handshake(&mut server_handle, hello).await?;
 
-    Ok(Pin::new(Box::new(socket)))
+    Ok(server_handle)
 }
@@ -1,9 +1,21 @@
 #[derive(Clone)]
 pub enum Action {
     None,
-    Login,
+    Login {
+        login: String,
+        password: String,
+    },
+    Logout,
+    JoinLobby,
+    LeaveLobby,
     ShowRegister,
-    Register,
+    Register {
+        login: String,
+        email: String,
+        password: String,
+    },
+    /// Register was pressed with a confirmation that differs from the password
+    PasswordsDiffer,
     Exit,
     PreExit,
     CancelExit,
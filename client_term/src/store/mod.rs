@@ -1,4 +1,4 @@
+#[allow(clippy::module_inception)]
 pub mod store;
 pub mod state;
 pub mod action;
-pub mod event;
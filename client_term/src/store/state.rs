@@ -1,17 +1,34 @@
+use std::time::Duration;
+
+use common::command::Profile;
+
 #[derive(Default, Clone)]
 pub enum ConnectionStatus {
     #[default]
     Idle,
-    // Connecting,
-    // Connected,
+    Connecting,
+    Connected,
     // Errored {
     //     message: String,
     // },
 }
 
+/// Session opened by a successful login
+#[derive(Clone)]
+pub struct Session {
+    /// Sent again to resume the session after a reconnection
+    pub token: String,
+    /// Seconds since the Unix epoch
+    pub expires_at: u64,
+    pub profile: Profile,
+    /// Whether the server has the session in the lobby, restored on resume
+    pub in_lobby: bool,
+}
+
 #[derive(Default, Clone)]
 pub struct State {
-    // pub is_logged: bool,
+    /// Set once logged in
+    pub session: Option<Session>,
     pub is_registering: bool,
     // pub login: String,
     // pub password: String,
@@ -21,19 +38,7 @@ pub struct State {
     // pub register_confirm_password: String,
     pub error_message: String,
     pub show_exit_confirmation: bool,
-    // pub connection_status: ConnectionStatus,
+    pub connection_status: ConnectionStatus,
+    /// Round trip time to the server, when connected and measured
+    pub latency: Option<Duration>,
 }
-
-// impl State {
-//     pub fn handle_server_event(&mut self, event: &event::Event) {
-//         match event {
-//             event::Event::RegisterResponse(event::RegisterResponseEvent { success, message }) => {
-//                 self.connection_status = ConnectionStatus::Idle;
-//                 self.error_message = message.clone();
-//                 if *success {
-//                     self.is_registering = false;
-//                 }
-//             }
-//         }
-//     }
-// }
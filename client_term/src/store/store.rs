@@ -1,27 +1,83 @@
-use std::time::Duration;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
 
-use tokio::io::AsyncReadExt;
-use tokio::net::TcpStream;
+use tokio::time::Instant;
 
+use common::command::{
+    ErrorCode,
+    ErrorCommand,
+    LoginCommand,
+    LoginResponseCommand,
+    RegisterCommand,
+    RegisterResponseCommand,
+    ResumeCommand,
+    ResumeResponseCommand,
+    ServerCommand,
+    UserCommand,
+};
+use common::connection::ReceiveError;
+use common::envelope::{ Envelope, PendingRequests };
 use tokio::sync::{ broadcast, mpsc };
 
-use std::pin::Pin;
-
-use crate::network;
+use crate::network::{ self, ServerHandle };
 use crate::termination::{ Interrupted, Terminator };
 
 use super::action::Action;
-use super::state::State;
+use super::state::{ ConnectionStatus, Session, State };
 
-pub struct Store {
-    state_sender: mpsc::UnboundedSender<State>,
+/// What a request in flight was sent for, used to interpret its reply
+#[derive(Debug, Clone, Copy)]
+enum PendingRequest {
+    Register,
+    Login,
+    Resume,
+    Logout,
+    JoinLobby,
+    LeaveLobby,
 }
 
-pub type SSLStream = tokio_native_tls::TlsStream<TcpStream>;
+/// First delay before reconnecting to a lost server, doubled on every failure
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
 
-type ServerHandle = Pin<Box<SSLStream>>;
+/// Longest wait for the connection and handshake, the interface does not
+/// respond in the meantime
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
-// pub const NEW_LINE: &[u8; 2] = b"\r\n";
+/// When to try reconnecting after losing the connection while logged in
+struct Reconnect {
+    at: Option<Instant>,
+    delay: Duration,
+}
+
+impl Reconnect {
+    fn new() -> Self {
+        Self {
+            at: None,
+            delay: RECONNECT_DELAY,
+        }
+    }
+
+    fn schedule(&mut self) {
+        self.at = Some(Instant::now() + self.delay);
+    }
+
+    fn is_due(&self) -> bool {
+        self.at.is_some_and(|at| at <= Instant::now())
+    }
+
+    fn failed(&mut self) {
+        self.delay = (self.delay * 2).min(MAX_RECONNECT_DELAY);
+        self.schedule();
+    }
+
+    fn succeeded(&mut self) {
+        *self = Self::new();
+    }
+}
+
+pub struct Store {
+    state_sender: mpsc::UnboundedSender<State>,
+}
 
 impl Store {
     pub fn new() -> (Self, mpsc::UnboundedReceiver<State>) {
@@ -40,66 +96,375 @@ impl Store {
 
         self.state_sender.send(state.clone())?;
 
-        let _ticker = tokio::time::interval(Duration::from_secs(1));
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
 
         let mut opt_server_handle: Option<ServerHandle> = None;
+        let mut pending_requests: PendingRequests<PendingRequest> = PendingRequests::new();
+        let mut reconnect = Reconnect::new();
 
         let result = loop {
-            if let Some(server) = opt_server_handle.as_mut() {
-                let mut buffer = [0; 10];
-                tokio::select! {
-                    data = server.read(&mut buffer) => match data {
-                        Ok(0) => {
-                            break Interrupted::UserInt;
-                        },
-                        Ok(n) => {
-                            let data = &buffer[..n];
-                            let data = std::str::from_utf8(data).unwrap();
-                            println!("Received: {}", data);
-                        },
-                        Err(e) => {
-                            eprintln!("Error reading from server: {}", e);
-                            break Interrupted::UserInt;
-                        }
+            tokio::select! {
+                envelope = receive(&mut opt_server_handle), if opt_server_handle.is_some() => match envelope {
+                    Ok(envelope) => {
+                        handle_server_envelope(&mut state, &mut pending_requests, envelope);
+                        self.state_sender.send(state.clone())?;
                     },
-                }
-            } else {
-                tokio::select! {
-                    Some(action) = action_receiver.recv() => match action {
-                        Action::None => {
-                        },
-                        Action::Login => {
-                        },
-                        Action::ShowRegister => {
-                            state.is_registering = true;
-                            self.state_sender.send(state.clone())?;
-                        },
-                        Action::Register => {
-                            match network::create_server_handle().await {
-                                Ok(server_handle) => {
-                                    opt_server_handle = Some(server_handle);
-                                },
-                                Err(e) => {
-                                    state.error_message = e.to_string();
-                                }
-                            }
-                        },
-                        Action::PreExit => {
-                            state.show_exit_confirmation = true;
-                            self.state_sender.send(state.clone())?;
-                        },
-                        Action::CancelExit => {
-                            state.show_exit_confirmation = false;
-                            self.state_sender.send(state.clone())?;
-                        },
-                        Action::Exit => {
-                            terminator.terminate(Interrupted::UserInt)?;
-                            break Interrupted::UserInt;
-                        },
+                    Err(e) if e.is_bad_frame() => {
+                        state.error_message = e.to_string();
+                        self.state_sender.send(state.clone())?;
+                    },
+                    Err(e) => {
+                        let was_waiting = !pending_requests.is_empty();
+                        let unexpected = !matches!(e, ReceiveError::Closed);
+                        if was_waiting || unexpected {
+                            state.error_message = e.to_string();
+                        }
+                        disconnected(&mut state, &mut opt_server_handle, &mut pending_requests, &mut reconnect);
+                        self.state_sender.send(state.clone())?;
+                    }
+                },
+                _ = ticker.tick() => {
+                    if reconnect.is_due() && state.session.is_some() {
+                        self.connect(&mut state, &mut opt_server_handle, &mut pending_requests, &mut reconnect).await?;
+                        match opt_server_handle.is_some() {
+                            true => reconnect.succeeded(),
+                            false => reconnect.failed(),
+                        }
+                    }
+                    let latency = opt_server_handle
+                        .as_ref()
+                        .and_then(|server_handle| server_handle.round_trip_time());
+                    let expired = state.session
+                        .as_ref()
+                        .is_some_and(|session| session.expires_at <= unix_now());
+                    if expired {
+                        state.session = None;
+                        state.error_message = String::from("Session expired, please log in again");
                     }
+                    if latency != state.latency || expired {
+                        state.latency = latency;
+                        self.state_sender.send(state.clone())?;
+                    }
+                },
+                Some(action) = action_receiver.recv() => match action {
+                    Action::None => {
+                    },
+                    Action::Login { login, password } => {
+                        self.request(
+                            &mut state,
+                            &mut opt_server_handle,
+                            &mut pending_requests,
+                            &mut reconnect,
+                            PendingRequest::Login,
+                            UserCommand::Login(LoginCommand { login, password })
+                        ).await?;
+                    },
+                    Action::Logout => {
+                        if state.session.is_some() {
+                            // Reconnects first when needed, the session must
+                            // not stay usable on the server
+                            self.request(
+                                &mut state,
+                                &mut opt_server_handle,
+                                &mut pending_requests,
+                                &mut reconnect,
+                                PendingRequest::Logout,
+                                UserCommand::Logout
+                            ).await?;
+                        }
+                        state.error_message = match opt_server_handle {
+                            Some(_) => String::new(),
+                            None => String::from("Logged out here only, the server could not be reached"),
+                        };
+                        reconnect.succeeded();
+                        state.session = None;
+                        self.state_sender.send(state.clone())?;
+                    },
+                    Action::JoinLobby => {
+                        self.request(
+                            &mut state,
+                            &mut opt_server_handle,
+                            &mut pending_requests,
+                            &mut reconnect,
+                            PendingRequest::JoinLobby,
+                            UserCommand::JoinLobby
+                        ).await?;
+                    },
+                    Action::LeaveLobby => {
+                        self.request(
+                            &mut state,
+                            &mut opt_server_handle,
+                            &mut pending_requests,
+                            &mut reconnect,
+                            PendingRequest::LeaveLobby,
+                            UserCommand::LeaveLobby
+                        ).await?;
+                    },
+                    Action::ShowRegister => {
+                        state.is_registering = true;
+                        self.state_sender.send(state.clone())?;
+                    },
+                    Action::Register { login, email, password } => {
+                        self.request(
+                            &mut state,
+                            &mut opt_server_handle,
+                            &mut pending_requests,
+                            &mut reconnect,
+                            PendingRequest::Register,
+                            UserCommand::Register(RegisterCommand {
+                                login,
+                                email,
+                                password,
+                                locale: RegisterCommand::system_locale(),
+                            })
+                        ).await?;
+                    },
+                    Action::PasswordsDiffer => {
+                        state.error_message = String::from("The passwords do not match");
+                        self.state_sender.send(state.clone())?;
+                    },
+                    Action::PreExit => {
+                        state.show_exit_confirmation = true;
+                        self.state_sender.send(state.clone())?;
+                    },
+                    Action::CancelExit => {
+                        state.show_exit_confirmation = false;
+                        self.state_sender.send(state.clone())?;
+                    },
+                    Action::Exit => {
+                        terminator.terminate(Interrupted::UserInt)?;
+                        break Interrupted::UserInt;
+                    },
                 }
             }
         };
         Ok(result)
     }
+
+    /// Connect to the server and, when logged in, resume the session on the
+    /// new connection. Leaves `opt_server_handle` empty if connecting failed.
+    async fn connect(
+        &self,
+        state: &mut State,
+        opt_server_handle: &mut Option<ServerHandle>,
+        pending_requests: &mut PendingRequests<PendingRequest>,
+        reconnect: &mut Reconnect
+    ) -> anyhow::Result<()> {
+        state.connection_status = ConnectionStatus::Connecting;
+        self.state_sender.send(state.clone())?;
+        match tokio::time::timeout(CONNECT_TIMEOUT, network::create_server_handle()).await {
+            Ok(Ok(server_handle)) => {
+                *opt_server_handle = Some(server_handle);
+                state.connection_status = ConnectionStatus::Connected;
+            }
+            Ok(Err(e)) => {
+                state.connection_status = ConnectionStatus::Idle;
+                state.error_message = e.to_string();
+            }
+            Err(_) => {
+                state.connection_status = ConnectionStatus::Idle;
+                state.error_message = String::from("The server did not answer in time");
+            }
+        }
+        self.state_sender.send(state.clone())?;
+
+        if let Some(session) = &state.session {
+            let session_token = session.token.clone();
+            self.send(
+                state,
+                opt_server_handle,
+                pending_requests,
+                reconnect,
+                PendingRequest::Resume,
+                UserCommand::Resume(ResumeCommand { session_token })
+            ).await?;
+        }
+        Ok(())
+    }
+
+    /// Send `command` to the server, connecting first if needed, and keep
+    /// `request` until the matching reply comes back.
+    async fn request(
+        &self,
+        state: &mut State,
+        opt_server_handle: &mut Option<ServerHandle>,
+        pending_requests: &mut PendingRequests<PendingRequest>,
+        reconnect: &mut Reconnect,
+        request: PendingRequest,
+        command: UserCommand
+    ) -> anyhow::Result<()> {
+        if opt_server_handle.is_none() {
+            self.connect(state, opt_server_handle, pending_requests, reconnect).await?;
+        }
+        self.send(state, opt_server_handle, pending_requests, reconnect, request, command).await
+    }
+
+    async fn send(
+        &self,
+        state: &mut State,
+        opt_server_handle: &mut Option<ServerHandle>,
+        pending_requests: &mut PendingRequests<PendingRequest>,
+        reconnect: &mut Reconnect,
+        request: PendingRequest,
+        command: UserCommand
+    ) -> anyhow::Result<()> {
+        if let Some(server_handle) = opt_server_handle.as_mut() {
+            let id = pending_requests.register(request);
+            if let Err(e) = server_handle.send(&Envelope::request(id, command)).await {
+                state.error_message = e.to_string();
+                disconnected(state, opt_server_handle, pending_requests, reconnect);
+                self.state_sender.send(state.clone())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Forget the lost connection and the requests sent on it
+fn disconnected(
+    state: &mut State,
+    opt_server_handle: &mut Option<ServerHandle>,
+    pending_requests: &mut PendingRequests<PendingRequest>,
+    reconnect: &mut Reconnect
+) {
+    *opt_server_handle = None;
+    pending_requests.clear();
+    state.connection_status = ConnectionStatus::Idle;
+    // The server keeps the connection open, losing it while logged in
+    // always calls for a reconnection
+    if state.session.is_some() {
+        reconnect.schedule();
+    }
+}
+
+/// Receive from the server if connected. Only polled while a handle exists.
+async fn receive(
+    opt_server_handle: &mut Option<ServerHandle>
+) -> Result<Envelope<ServerCommand>, ReceiveError> {
+    match opt_server_handle {
+        Some(server_handle) => server_handle.receive().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn handle_server_envelope(
+    state: &mut State,
+    pending_requests: &mut PendingRequests<PendingRequest>,
+    envelope: Envelope<ServerCommand>
+) {
+    if envelope.push {
+        handle_push(state, envelope.command);
+        return;
+    }
+    match envelope.id.and_then(|id| pending_requests.resolve(id)) {
+        Some(request) => handle_reply(state, request, envelope.command),
+        None => handle_unmatched_reply(state, envelope.command),
+    }
+}
+
+fn handle_reply(state: &mut State, request: PendingRequest, command: ServerCommand) {
+    match (request, command) {
+        (
+            PendingRequest::Register,
+            ServerCommand::RegisterResponse(RegisterResponseCommand { email_queued, rejection }),
+        ) => {
+            state.error_message = match (rejection, email_queued) {
+                (Some(rejection), _) => rejection.message().to_string(),
+                (None, true) => String::from("Registration email on its way, check your inbox"),
+                (None, false) => String::from("Registration email could not be sent"),
+            };
+        }
+        (
+            PendingRequest::Login,
+            ServerCommand::LoginResponse(LoginResponseCommand {
+                session_token,
+                expires_at,
+                profile,
+            }),
+        ) => {
+            state.error_message = String::new();
+            state.is_registering = false;
+            state.session = Some(Session {
+                token: session_token,
+                expires_at,
+                profile,
+                in_lobby: false,
+            });
+        }
+        (
+            PendingRequest::Resume,
+            ServerCommand::ResumeResponse(ResumeResponseCommand { expires_at, profile, in_lobby, .. }),
+        ) => {
+            state.error_message = String::new();
+            if let Some(session) = state.session.as_mut() {
+                session.expires_at = expires_at;
+                session.profile = profile;
+                session.in_lobby = in_lobby;
+            }
+        }
+        (PendingRequest::JoinLobby, ServerCommand::JoinLobbyResponse) => {
+            set_in_lobby(state, true);
+        }
+        (PendingRequest::LeaveLobby, ServerCommand::LeaveLobbyResponse) => {
+            set_in_lobby(state, false);
+        }
+        (PendingRequest::Logout, ServerCommand::LogoutResponse) => {
+            // The session was already dropped locally
+        }
+        (PendingRequest::Resume, ServerCommand::Error(error)) if
+            matches!(
+                error.code,
+                ErrorCode::InvalidSession |
+                    ErrorCode::AccountNotConfirmed |
+                    ErrorCode::AccountSuspended |
+                    ErrorCode::AccountDeleted
+            )
+        => {
+            state.session = None;
+            state.error_message = describe_error(&error);
+        }
+        (_, ServerCommand::Error(error)) => {
+            state.error_message = describe_error(&error);
+        }
+        (request, command) => {
+            state.error_message = format!("Unexpected answer to {:?}: {:?}", request, command);
+        }
+    }
+}
+
+fn set_in_lobby(state: &mut State, in_lobby: bool) {
+    state.error_message = String::new();
+    if let Some(session) = state.session.as_mut() {
+        session.in_lobby = in_lobby;
+    }
+}
+
+fn handle_push(state: &mut State, command: ServerCommand) {
+    state.error_message = format!("Unexpected notification from server: {:?}", command);
+}
+
+/// Replies whose request is unknown, such as answers to unreadable frames
+fn handle_unmatched_reply(state: &mut State, command: ServerCommand) {
+    match command {
+        ServerCommand::Error(error) => {
+            state.error_message = describe_error(&error);
+        }
+        command => {
+            state.error_message = format!("Unexpected message from server: {:?}", command);
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+fn describe_error(error: &ErrorCommand) -> String {
+    match error.retryable {
+        true => format!("{}, please try again later", error.message),
+        false => error.message.clone(),
+    }
 }
@@ -1,7 +1,9 @@
+use std::time::Duration;
+
 use ratatui::{
     layout::{ Alignment, Layout },
     style::{ Color, Style },
-    widgets::{ Block, Borders, Paragraph },
+    widgets::{ block::{ Position, Title }, Block, Borders, Paragraph },
     Frame,
 };
 use tokio::sync::mpsc::UnboundedSender;
@@ -10,24 +12,31 @@ use crate::store::{ action::Action, state::State };
 
 use super::{
     exit_modal::ExitModal,
+    home_page::HomePage,
+    lobby_page::LobbyPage,
     login_page::LoginPage,
     register_page::RegisterPage,
     ui_object::{ UIObject, UIRender },
 };
 
 enum ActivePage {
-    LoginPage,
-    RegisterPage,
+    Login,
+    Register,
+    Home,
+    Lobby,
 }
 
 pub struct Application {
     login_page: LoginPage,
     register_page: RegisterPage,
+    home_page: HomePage,
+    lobby_page: LobbyPage,
     exit_modal: ExitModal,
     active_page: ActivePage,
     action_sender: UnboundedSender<Action>,
     show_exit_modal: bool,
     error_message: String,
+    latency: Option<Duration>,
 }
 
 impl UIObject<()> for Application {
@@ -35,11 +44,14 @@ impl UIObject<()> for Application {
         Self {
             login_page: LoginPage::new(state, action_sender.clone(), ()),
             register_page: RegisterPage::new(state, action_sender.clone(), ()),
+            home_page: HomePage::new(state, action_sender.clone(), ()),
+            lobby_page: LobbyPage::new(state, action_sender.clone(), ()),
             exit_modal: ExitModal::new(state, action_sender.clone(), ()),
-            active_page: ActivePage::LoginPage,
+            active_page: ActivePage::Login,
             action_sender,
             show_exit_modal: false,
             error_message: String::new(),
+            latency: None,
         }
     }
 
@@ -47,25 +59,27 @@ impl UIObject<()> for Application {
         Self {
             login_page: self.login_page.move_with_state(state),
             register_page: self.register_page.move_with_state(state),
-            active_page: match state.is_registering {
-                false => ActivePage::LoginPage,
-                true => ActivePage::RegisterPage,
+            home_page: self.home_page.move_with_state(state),
+            lobby_page: self.lobby_page.move_with_state(state),
+            active_page: match (&state.session, state.is_registering) {
+                (Some(session), _) if session.in_lobby => ActivePage::Lobby,
+                (Some(_), _) => ActivePage::Home,
+                (None, false) => ActivePage::Login,
+                (None, true) => ActivePage::Register,
             },
             action_sender: self.action_sender,
             exit_modal: self.exit_modal.move_with_state(state),
             show_exit_modal: state.show_exit_confirmation,
             error_message: state.error_message.clone(),
+            latency: state.latency,
         }
     }
 
     fn handle_key_event(&mut self, event: crossterm::event::Event) {
-        match event {
-            crossterm::event::Event::Key(key) => {
-                if key.code == crossterm::event::KeyCode::Esc {
-                    self.action_sender.send(Action::PreExit).unwrap();
-                }
+        if let crossterm::event::Event::Key(key) = event {
+            if key.code == crossterm::event::KeyCode::Esc {
+                self.action_sender.send(Action::PreExit).unwrap();
             }
-            _ => {}
         }
 
         if self.show_exit_modal {
@@ -74,8 +88,10 @@ impl UIObject<()> for Application {
         }
 
         match self.active_page {
-            ActivePage::LoginPage => self.login_page.handle_key_event(event),
-            ActivePage::RegisterPage => self.register_page.handle_key_event(event),
+            ActivePage::Login => self.login_page.handle_key_event(event),
+            ActivePage::Register => self.register_page.handle_key_event(event),
+            ActivePage::Home => self.home_page.handle_key_event(event),
+            ActivePage::Lobby => self.lobby_page.handle_key_event(event),
         }
     }
 }
@@ -83,8 +99,13 @@ impl UIObject<()> for Application {
 impl UIRender<()> for Application {
     fn render(&self, frame: &mut Frame, properties: ()) {
         // PAGE BLOCK
+        let latency_label = match self.latency {
+            Some(latency) => format!(" {} ms ", latency.as_millis()),
+            None => String::new(),
+        };
         let page_block = Block::default()
             .title("Termplay")
+            .title(Title::from(latency_label).alignment(Alignment::Right).position(Position::Bottom))
             .title_alignment(Alignment::Center)
             .borders(Borders::ALL)
             .border_style(Style::default().fg(Color::White))
@@ -123,8 +144,10 @@ impl UIRender<()> for Application {
 
         // CURRENT PAGE
         match self.active_page {
-            ActivePage::LoginPage => self.login_page.render(frame, properties),
-            ActivePage::RegisterPage => self.register_page.render(frame, properties),
+            ActivePage::Login => self.login_page.render(frame, properties),
+            ActivePage::Register => self.register_page.render(frame, properties),
+            ActivePage::Home => self.home_page.render(frame, properties),
+            ActivePage::Lobby => self.lobby_page.render(frame, properties),
         }
 
         // EXIT MODAL
@@ -37,18 +37,12 @@ impl UIObject<InitProperties> for Button {
     }
 
     fn handle_key_event(&mut self, event: crossterm::event::Event) {
-        match event {
-            crossterm::event::Event::Key(key) => {
-                match key.code {
-                    crossterm::event::KeyCode::Enter => {
-                        if let Err(err) = self.action_sender.send(self.action_to_send.clone()) {
-                            eprintln!("Failed to send action: {:?}", err);
-                        }
-                    }
-                    _ => {}
+        if let crossterm::event::Event::Key(key) = event {
+            if key.code == crossterm::event::KeyCode::Enter {
+                if let Err(err) = self.action_sender.send(self.action_to_send.clone()) {
+                    eprintln!("Failed to send action: {:?}", err);
                 }
             }
-            _ => {}
         }
     }
 }
@@ -51,47 +51,44 @@ impl UIObject<()> for ExitModal {
     }
 
     fn handle_key_event(&mut self, event: crossterm::event::Event) {
-        match event {
-            crossterm::event::Event::Key(key) => {
-                if key.kind != KeyEventKind::Press {
-                    return;
+        if let crossterm::event::Event::Key(key) = event {
+            if key.kind != KeyEventKind::Press {
+                return;
+            }
+            match key.code {
+                crossterm::event::KeyCode::Esc => {
+                    self.action_sender.send(Action::CancelExit).unwrap();
                 }
-                match key.code {
-                    crossterm::event::KeyCode::Esc => {
-                        self.action_sender.send(Action::CancelExit).unwrap();
-                    }
-                    crossterm::event::KeyCode::Tab => {
-                        self.last_hovered_section = utils::cycle(
-                            Focus::YesButton,
-                            Focus::NoButton,
-                            self.last_hovered_section,
-                            1
-                        );
-                    }
-                    crossterm::event::KeyCode::BackTab => {
-                        self.last_hovered_section = utils::cycle(
-                            Focus::YesButton,
-                            Focus::NoButton,
-                            self.last_hovered_section,
-                            -1
-                        );
-                    }
-                    _ => {
-                        let active_section = self.active_section
-                            .as_ref()
-                            .unwrap_or(&self.last_hovered_section);
-                        match active_section {
-                            Focus::YesButton => {
-                                self.yes_button.handle_key_event(event);
-                            }
-                            Focus::NoButton => {
-                                self.no_button.handle_key_event(event);
-                            }
+                crossterm::event::KeyCode::Tab => {
+                    self.last_hovered_section = utils::cycle(
+                        Focus::YesButton,
+                        Focus::NoButton,
+                        self.last_hovered_section,
+                        1
+                    );
+                }
+                crossterm::event::KeyCode::BackTab => {
+                    self.last_hovered_section = utils::cycle(
+                        Focus::YesButton,
+                        Focus::NoButton,
+                        self.last_hovered_section,
+                        -1
+                    );
+                }
+                _ => {
+                    let active_section = self.active_section
+                        .as_ref()
+                        .unwrap_or(&self.last_hovered_section);
+                    match active_section {
+                        Focus::YesButton => {
+                            self.yes_button.handle_key_event(event);
+                        }
+                        Focus::NoButton => {
+                            self.no_button.handle_key_event(event);
                         }
                     }
                 }
             }
-            _ => {}
         }
     }
 }
@@ -118,7 +115,7 @@ impl UIRender<()> for ExitModal {
 
         let mut modal_area = areas_center_3[1];
 
-        frame.render_widget(Clear::default(), modal_area);
+        frame.render_widget(Clear, modal_area);
 
         let modal_block = Block::default()
             .title(self.label.to_string())
@@ -0,0 +1,177 @@
+use crossterm::event::KeyEventKind;
+use ratatui::layout::{ Alignment, Constraint, Direction, Layout };
+use ratatui::style::{ Color, Style };
+use ratatui::widgets::{ Block, Borders, Paragraph };
+use ratatui::Frame;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::store::action::Action;
+use crate::store::state::State;
+use crate::ui::button::{ self, Button };
+use crate::ui::ui_object::{ UIObject, UIRender };
+
+use super::home_page::login_of;
+use super::utils;
+
+#[derive(FromPrimitive, ToPrimitive, Eq, PartialEq, Clone, Copy)]
+pub enum Focus {
+    LeaveLobbyButton,
+    LogoutButton,
+}
+
+const DEFAULT_HOVERED_SECTION: Focus = Focus::LeaveLobbyButton;
+
+/// Where a logged in user waits for a game
+pub struct LobbyPage {
+    login: String,
+    leave_lobby_button: Button,
+    logout_button: Button,
+    last_hovered_section: Focus,
+    active_section: Option<Focus>,
+}
+
+impl UIObject<()> for LobbyPage {
+    fn new(state: &State, action_sender: UnboundedSender<Action>, _: ()) -> Self {
+        Self {
+            login: login_of(state),
+            leave_lobby_button: Button::new(state, action_sender.clone(), button::InitProperties {
+                label: String::from("Leave lobby"),
+                action_to_send: Action::LeaveLobby,
+            }),
+            logout_button: Button::new(state, action_sender.clone(), button::InitProperties {
+                label: String::from("Logout"),
+                action_to_send: Action::Logout,
+            }),
+            last_hovered_section: DEFAULT_HOVERED_SECTION,
+            active_section: None,
+        }
+    }
+
+    fn move_with_state(self, state: &State) -> Self {
+        Self {
+            login: login_of(state),
+            leave_lobby_button: self.leave_lobby_button.move_with_state(state),
+            logout_button: self.logout_button.move_with_state(state),
+            last_hovered_section: self.last_hovered_section,
+            active_section: self.active_section,
+        }
+    }
+
+    fn handle_key_event(&mut self, event: crossterm::event::Event) {
+        if let crossterm::event::Event::Key(key) = event {
+            if key.kind != KeyEventKind::Press {
+                return;
+            }
+            match key.code {
+                crossterm::event::KeyCode::Tab => {
+                    self.last_hovered_section = utils::cycle(
+                        Focus::LeaveLobbyButton,
+                        Focus::LogoutButton,
+                        self.last_hovered_section,
+                        1
+                    );
+                }
+                crossterm::event::KeyCode::BackTab => {
+                    self.last_hovered_section = utils::cycle(
+                        Focus::LeaveLobbyButton,
+                        Focus::LogoutButton,
+                        self.last_hovered_section,
+                        -1
+                    );
+                }
+                _ => {
+                    let active_section = self.active_section
+                        .as_ref()
+                        .unwrap_or(&self.last_hovered_section);
+                    match active_section {
+                        Focus::LeaveLobbyButton => {
+                            self.leave_lobby_button.handle_key_event(event);
+                        }
+                        Focus::LogoutButton => {
+                            self.logout_button.handle_key_event(event);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl UIRender<()> for LobbyPage {
+    fn render(&self, frame: &mut Frame, _properties: ()) {
+        let areas_vert_3 = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Percentage(40),
+                Constraint::Percentage(20),
+                Constraint::Percentage(40),
+            ])
+            .split(frame.size());
+
+        let areas_center_3 = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([
+                Constraint::Percentage(25),
+                Constraint::Percentage(50),
+                Constraint::Percentage(25),
+            ])
+            .split(areas_vert_3[1]);
+
+        let mut modal_area = areas_center_3[1];
+
+        let modal_block = Block::default()
+            .title("Lobby")
+            .title_alignment(Alignment::Center)
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(Color::White))
+            .style(Style::default().bg(Color::Black));
+
+        // RENDER MODAL BLOCK
+        frame.render_widget(modal_block, modal_area);
+
+        modal_area.x += 4;
+        modal_area.y += 2;
+        modal_area.width -= 8;
+        modal_area.height -= 4;
+
+        let modal_areas_vert_2 = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Percentage(100), Constraint::Min(3)])
+            .split(modal_area);
+
+        // RENDER GREETING
+        let greeting = Paragraph::new(format!("{}, waiting for a game to start", self.login)).alignment(
+            Alignment::Center
+        );
+        frame.render_widget(greeting, modal_areas_vert_2[0]);
+
+        let modal_buttons_areas_horiz_3 = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Min(15), Constraint::Percentage(100), Constraint::Min(15)])
+            .split(modal_areas_vert_2[1]);
+
+        let mut leave_lobby_button_area = modal_buttons_areas_horiz_3[0];
+        leave_lobby_button_area.height = 3;
+        // RENDER LEAVE LOBBY BUTTON
+        self.leave_lobby_button.render(frame, button::RenderProperties {
+            border_color: utils::calculate_border_color(
+                self.active_section,
+                self.last_hovered_section,
+                Focus::LeaveLobbyButton
+            ),
+            area: leave_lobby_button_area,
+        });
+
+        let mut logout_button_area = modal_buttons_areas_horiz_3[2];
+        logout_button_area.height = 3;
+        // RENDER LOGOUT BUTTON
+        self.logout_button.render(frame, button::RenderProperties {
+            border_color: utils::calculate_border_color(
+                self.active_section,
+                self.last_hovered_section,
+                Focus::LogoutButton
+            ),
+            area: logout_button_area,
+        });
+    }
+}
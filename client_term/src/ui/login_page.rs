@@ -61,7 +61,7 @@ impl UIObject<()> for LoginPage {
             ),
             login_button: Button::new(state, action_sender.clone(), button::InitProperties {
                 label: String::from("Login"),
-                action_to_send: Action::Login,
+                action_to_send: Action::None,
             }),
             register_button: Button::new(state, action_sender.clone(), button::InitProperties {
                 label: String::from("Register"),
@@ -89,53 +89,54 @@ impl UIObject<()> for LoginPage {
     }
 
     fn handle_key_event(&mut self, event: crossterm::event::Event) {
-        match event {
-            crossterm::event::Event::Key(key) => {
-                if key.kind != KeyEventKind::Press {
-                    return;
+        if let crossterm::event::Event::Key(key) = event {
+            if key.kind != KeyEventKind::Press {
+                return;
+            }
+            match key.code {
+                crossterm::event::KeyCode::Tab => {
+                    self.last_hovered_section = utils::cycle(
+                        Focus::LoginField,
+                        Focus::ExitButton,
+                        self.last_hovered_section,
+                        1
+                    );
                 }
-                match key.code {
-                    crossterm::event::KeyCode::Tab => {
-                        self.last_hovered_section = utils::cycle(
-                            Focus::LoginField,
-                            Focus::ExitButton,
-                            self.last_hovered_section,
-                            1
-                        );
-                    }
-                    crossterm::event::KeyCode::BackTab => {
-                        self.last_hovered_section = utils::cycle(
-                            Focus::LoginField,
-                            Focus::ExitButton,
-                            self.last_hovered_section,
-                            -1
-                        );
-                    }
-                    _ => {
-                        let active_section = self.active_section
-                            .as_ref()
-                            .unwrap_or(&self.last_hovered_section);
-                        match active_section {
-                            Focus::LoginField => {
-                                self.login_field.handle_key_event(event);
-                            }
-                            Focus::PasswordField => {
-                                self.password_field.handle_key_event(event);
-                            }
-                            Focus::LoginButton => {
-                                self.login_button.handle_key_event(event);
-                            }
-                            Focus::RegisterButton => {
-                                self.register_button.handle_key_event(event);
-                            }
-                            Focus::ExitButton => {
-                                self.exit_button.handle_key_event(event);
-                            }
+                crossterm::event::KeyCode::BackTab => {
+                    self.last_hovered_section = utils::cycle(
+                        Focus::LoginField,
+                        Focus::ExitButton,
+                        self.last_hovered_section,
+                        -1
+                    );
+                }
+                _ => {
+                    let active_section = self.active_section
+                        .as_ref()
+                        .unwrap_or(&self.last_hovered_section);
+                    match active_section {
+                        Focus::LoginField => {
+                            self.login_field.handle_key_event(event);
+                        }
+                        Focus::PasswordField => {
+                            self.password_field.handle_key_event(event);
+                        }
+                        Focus::LoginButton => {
+                            self.login_button.action_to_send = Action::Login {
+                                login: self.login_field.text().to_string(),
+                                password: self.password_field.text().to_string(),
+                            };
+                            self.login_button.handle_key_event(event);
+                        }
+                        Focus::RegisterButton => {
+                            self.register_button.handle_key_event(event);
+                        }
+                        Focus::ExitButton => {
+                            self.exit_button.handle_key_event(event);
                         }
                     }
                 }
             }
-            _ => {}
         }
     }
 }
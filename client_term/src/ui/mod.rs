@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 pub mod ui;
 pub mod login_page;
 pub mod ui_object;
@@ -5,5 +6,7 @@ pub mod text_input;
 pub mod button;
 pub mod application;
 pub mod register_page;
+pub mod home_page;
+pub mod lobby_page;
 pub mod exit_modal;
 pub mod utils;
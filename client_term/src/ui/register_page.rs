@@ -6,6 +6,7 @@ use ratatui::{
     widgets::{ Block, Borders },
     Frame,
 };
+use common::command::MAX_EMAIL_LENGTH;
 use tokio::sync::mpsc::UnboundedSender;
 
 use crate::store::{ action::Action, state::State };
@@ -20,7 +21,7 @@ use super::{
 #[derive(FromPrimitive, ToPrimitive, Eq, PartialEq, Clone, Copy)]
 pub enum Focus {
     LoginField,
-    ConfirmLoginField,
+    EmailField,
     PasswordField,
     ConfirmPasswordField,
     BackButton,
@@ -31,7 +32,7 @@ const DEFAULT_HOVERED_SECTION: Focus = Focus::LoginField;
 
 pub struct RegisterPage {
     login_field: TextInput,
-    confirm_login_field: TextInput,
+    email_field: TextInput,
     password_field: TextInput,
     confirm_password_field: TextInput,
     back_button: Button,
@@ -57,11 +58,11 @@ impl UIObject<()> for RegisterPage {
                 cursor_limit: 20,
                 is_password: false,
             }),
-            confirm_login_field: TextInput::new(
+            email_field: TextInput::new(
                 state,
                 action_sender.clone(),
                 text_input::InitProperties {
-                    cursor_limit: 20,
+                    cursor_limit: MAX_EMAIL_LENGTH,
                     is_password: false,
                 }
             ),
@@ -90,7 +91,11 @@ impl UIObject<()> for RegisterPage {
                 action_sender.clone(),
                 super::button::InitProperties {
                     label: String::from_str("Register").unwrap(),
-                    action_to_send: Action::Register,
+                    action_to_send: Action::Register {
+                        login: String::new(),
+                        email: String::new(),
+                        password: String::new(),
+                    },
                 }
             ),
             last_hovered_section: DEFAULT_HOVERED_SECTION,
@@ -101,7 +106,7 @@ impl UIObject<()> for RegisterPage {
     fn move_with_state(self, state: &State) -> Self {
         Self {
             login_field: self.login_field.move_with_state(state),
-            confirm_login_field: self.confirm_login_field.move_with_state(state),
+            email_field: self.email_field.move_with_state(state),
             password_field: self.password_field.move_with_state(state),
             confirm_password_field: self.confirm_password_field.move_with_state(state),
             back_button: self.back_button.move_with_state(state),
@@ -112,56 +117,64 @@ impl UIObject<()> for RegisterPage {
     }
 
     fn handle_key_event(&mut self, event: crossterm::event::Event) {
-        match event {
-            crossterm::event::Event::Key(key) => {
-                if key.kind != crossterm::event::KeyEventKind::Press {
-                    return;
+        if let crossterm::event::Event::Key(key) = event {
+            if key.kind != crossterm::event::KeyEventKind::Press {
+                return;
+            }
+            match key.code {
+                crossterm::event::KeyCode::Tab => {
+                    self.last_hovered_section = utils::cycle(
+                        Focus::LoginField,
+                        Focus::RegisterButton,
+                        self.last_hovered_section,
+                        1
+                    );
                 }
-                match key.code {
-                    crossterm::event::KeyCode::Tab => {
-                        self.last_hovered_section = utils::cycle(
-                            Focus::LoginField,
-                            Focus::RegisterButton,
-                            self.last_hovered_section,
-                            1
-                        );
-                    }
-                    crossterm::event::KeyCode::BackTab => {
-                        self.last_hovered_section = utils::cycle(
-                            Focus::LoginField,
-                            Focus::RegisterButton,
-                            self.last_hovered_section,
-                            -1
-                        );
-                    }
-                    _ => {
-                        let active_section = self.active_section
-                            .as_ref()
-                            .unwrap_or(&self.last_hovered_section);
-                        match active_section {
-                            Focus::LoginField => {
-                                self.login_field.handle_key_event(event);
-                            }
-                            Focus::ConfirmLoginField => {
-                                self.confirm_login_field.handle_key_event(event);
-                            }
-                            Focus::PasswordField => {
-                                self.password_field.handle_key_event(event);
-                            }
-                            Focus::ConfirmPasswordField => {
-                                self.confirm_password_field.handle_key_event(event);
-                            }
-                            Focus::BackButton => {
-                                self.back_button.handle_key_event(event);
-                            }
-                            Focus::RegisterButton => {
-                                self.register_button.handle_key_event(event);
-                            }
+                crossterm::event::KeyCode::BackTab => {
+                    self.last_hovered_section = utils::cycle(
+                        Focus::LoginField,
+                        Focus::RegisterButton,
+                        self.last_hovered_section,
+                        -1
+                    );
+                }
+                _ => {
+                    let active_section = self.active_section
+                        .as_ref()
+                        .unwrap_or(&self.last_hovered_section);
+                    match active_section {
+                        Focus::LoginField => {
+                            self.login_field.handle_key_event(event);
+                        }
+                        Focus::EmailField => {
+                            self.email_field.handle_key_event(event);
+                        }
+                        Focus::PasswordField => {
+                            self.password_field.handle_key_event(event);
+                        }
+                        Focus::ConfirmPasswordField => {
+                            self.confirm_password_field.handle_key_event(event);
+                        }
+                        Focus::BackButton => {
+                            self.back_button.handle_key_event(event);
+                        }
+                        Focus::RegisterButton => {
+                            let confirmed =
+                                self.password_field.text() == self.confirm_password_field.text();
+                            self.register_button.action_to_send = match confirmed {
+                                true =>
+                                    Action::Register {
+                                        login: self.login_field.text().to_string(),
+                                        email: self.email_field.text().to_string(),
+                                        password: self.password_field.text().to_string(),
+                                    },
+                                false => Action::PasswordsDiffer,
+                            };
+                            self.register_button.handle_key_event(event);
                         }
                     }
                 }
             }
-            _ => {}
         }
     }
 }
@@ -229,18 +242,18 @@ impl UIRender<()> for RegisterPage {
             show_cursor: self.calculate_show_cursor(Focus::LoginField),
         });
 
-        let mut confirm_login_field_area = modal_areas_vert_6[1];
-        confirm_login_field_area.height = 3;
-        // RENDER CONFIRM LOGIN FIELD
-        self.confirm_login_field.render(frame, text_input::RenderProperties {
-            title: String::from("Confirm Login"),
-            area: confirm_login_field_area,
+        let mut email_field_area = modal_areas_vert_6[1];
+        email_field_area.height = 3;
+        // RENDER EMAIL FIELD
+        self.email_field.render(frame, text_input::RenderProperties {
+            title: String::from("Email"),
+            area: email_field_area,
             border_color: utils::calculate_border_color(
                 self.active_section,
                 self.last_hovered_section,
-                Focus::ConfirmLoginField
+                Focus::EmailField
             ),
-            show_cursor: self.calculate_show_cursor(Focus::ConfirmLoginField),
+            show_cursor: self.calculate_show_cursor(Focus::EmailField),
         });
 
         let mut password_field_area = modal_areas_vert_6[2];
@@ -39,30 +39,31 @@ impl UIObject<InitProperties> for TextInput {
     }
 
     fn handle_key_event(&mut self, event: crossterm::event::Event) {
-        match event {
-            crossterm::event::Event::Key(key) => {
-                match key.code {
-                    crossterm::event::KeyCode::Backspace => {
-                        self.delete_char();
-                    }
-                    crossterm::event::KeyCode::Left => {
-                        self.move_cursor_left();
-                    }
-                    crossterm::event::KeyCode::Right => {
-                        self.move_cursor_right();
-                    }
-                    crossterm::event::KeyCode::Char(to_insert) => {
-                        self.enter_char(to_insert);
-                    }
-                    _ => {}
+        if let crossterm::event::Event::Key(key) = event {
+            match key.code {
+                crossterm::event::KeyCode::Backspace => {
+                    self.delete_char();
                 }
+                crossterm::event::KeyCode::Left => {
+                    self.move_cursor_left();
+                }
+                crossterm::event::KeyCode::Right => {
+                    self.move_cursor_right();
+                }
+                crossterm::event::KeyCode::Char(to_insert) => {
+                    self.enter_char(to_insert);
+                }
+                _ => {}
             }
-            _ => {}
         }
     }
 }
 
 impl TextInput {
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
     fn move_cursor_left(&mut self) {
         let cursor_moved_left = self.cursor_position.saturating_sub(1);
         self.cursor_position = self.clamp_cursor(cursor_moved_left);
@@ -119,13 +120,16 @@ pub struct RenderProperties {
 
 impl UIRender<RenderProperties> for TextInput {
     fn render(&self, frame: &mut Frame, properties: RenderProperties) {
-        let text_to_render: String;
-        if self.is_password {
-            text_to_render = "*".repeat(self.text.len());
+        let text_to_render = if self.is_password {
+            "*".repeat(self.text.len())
         } else {
-            text_to_render = self.text.clone();
-        }
+            self.text.clone()
+        };
+        // Scrolled sideways once the cursor would leave the field
+        let last_column = properties.area.width.saturating_sub(3) as usize;
+        let offset = self.cursor_position.saturating_sub(last_column) as u16;
         let paragraph = Paragraph::new(text_to_render)
+            .scroll((0, offset))
             .style(Style::default().fg(Color::White))
             .block(
                 Block::default()
@@ -142,7 +146,7 @@ impl UIRender<RenderProperties> for TextInput {
             frame.set_cursor(
                 // Draw the cursor at the current position in the input field.
                 // This position is can be controlled via the left and right arrow key
-                properties.area.x + (self.cursor_position as u16) + 1,
+                properties.area.x + (self.cursor_position as u16) - offset + 1,
                 // Move one line down, from the border to the input line
                 properties.area.y + 1
             )
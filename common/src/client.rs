@@ -1,47 +1,39 @@
-use tokio::net::TcpStream;
-use tokio::io::{ AsyncBufReadExt, AsyncWriteExt, BufStream };
-use tokio_native_tls::TlsStream;
+use tokio::io::{ AsyncRead, AsyncWrite };
 
-use crate::command;
+use crate::command::{
+    HandshakeRejectedCommand,
+    HelloCommand,
+    ServerCommand,
+    UserCommand,
+    WelcomeCommand,
+};
+use crate::connection::Connection;
+use crate::envelope::{ Envelope, HANDSHAKE_REQUEST_ID };
 
-pub type ClientStream = BufStream<TlsStream<TcpStream>>;
+/// Client side of a connection: sends [UserCommand], receives [ServerCommand]
+pub type CommandManager<S> = Connection<Envelope<ServerCommand>, Envelope<UserCommand>, S>;
 
-pub struct CommandManager {
-    buf_stream: ClientStream,
-}
-
-pub const NEW_LINE: &[u8; 2] = b"\r\n";
-
-impl CommandManager {
-    pub fn new(buf_stream: ClientStream) -> Self {
-        Self { buf_stream }
-    }
-
-    /// Send a [crate::command::UserCommand] to the backing [TcpStream]
-    ///
-    /// # Cancel Safety
-    ///
-    /// This method is not cancellation safe. If it is used as the event
-    /// in a [tokio::select!] statement and some other
-    /// branch completes first, then the provided [crate::command::UserCommand] may have been
-    /// partially written, but future calls to `write` will start over
-    /// from the beginning of the buffer. Causing undefined behaviour.
-    pub async fn send(&mut self, command: &command::UserCommand) -> anyhow::Result<()> {
-        let mut serialized_bytes = serde_json::to_vec(command)?;
-        serialized_bytes.extend_from_slice(NEW_LINE);
-        self.buf_stream.write(&serialized_bytes).await?;
-        self.buf_stream.flush().await?;
-        Ok(())
-    }
-
-    pub async fn receive<T>(&mut self) -> anyhow::Result<command::ServerCommand> {
-        let mut buffer: Vec<u8> = Vec::new();
-        self.buf_stream.read_until(b'\n', &mut buffer).await?;
-        let str = std::str::from_utf8(&buffer)?;
-        println!("Received: {}", str);
-        match serde_json::from_slice(&buffer) {
-            Ok(cmd) => Ok(cmd),
-            Err(e) => Err(anyhow::anyhow!("Error deserializing command: {}", e)),
+/// Open the conversation with the server.
+///
+/// Sends `hello` as the first frame and waits for the server's verdict. A
+/// [ServerCommand::HandshakeRejected] is turned into an error carrying the
+/// server's reason so it can be shown to the user as is. On success the
+/// connection is switched to the codec picked by the server.
+pub async fn handshake<S>(
+    cmd_manager: &mut CommandManager<S>,
+    hello: HelloCommand
+) -> anyhow::Result<WelcomeCommand>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    cmd_manager.send(&Envelope::request(HANDSHAKE_REQUEST_ID, UserCommand::Hello(hello))).await?;
+    match cmd_manager.receive().await?.command {
+        ServerCommand::Welcome(welcome) => {
+            cmd_manager.set_codec(welcome.codec);
+            Ok(welcome)
+        }
+        ServerCommand::HandshakeRejected(HandshakeRejectedCommand { reason, .. }) => {
+            anyhow::bail!("Server rejected the connection: {}", reason)
         }
+        other => anyhow::bail!("Unexpected answer to hello: {:?}", other),
     }
 }
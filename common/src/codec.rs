@@ -0,0 +1,251 @@
+use std::fmt;
+
+use serde::{ de::DeserializeOwned, Deserialize, Serialize };
+
+/// Wire encoding of the commands exchanged over a [crate::connection::Connection].
+///
+/// Every connection starts in [Codec::Json]. A client that supports another
+/// codec lists its [Codec::capability] in its hello, and the server announces
+/// the codec both sides switch to right after the welcome.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    /// JSON frames terminated by [crate::connection::NEW_LINE]
+    #[default]
+    Json,
+    /// MessagePack frames prefixed by their length as a big endian `u32`
+    MessagePack,
+}
+
+impl Codec {
+    /// Name of the codec in hello and welcome capabilities
+    pub fn capability(self) -> &'static str {
+        match self {
+            Codec::Json => "codec/json",
+            Codec::MessagePack => "codec/msgpack",
+        }
+    }
+
+    pub fn from_capability(capability: &str) -> Option<Self> {
+        [Codec::Json, Codec::MessagePack]
+            .into_iter()
+            .find(|codec| codec.capability() == capability)
+    }
+
+    /// Serialize `value` as one complete frame, delimiter included
+    pub fn encode<T: Serialize>(self, value: &T, buffer: &mut Vec<u8>) -> anyhow::Result<()> {
+        match self {
+            Codec::Json => {
+                serde_json::to_writer(&mut *buffer, value)?;
+                buffer.extend_from_slice(crate::connection::NEW_LINE);
+            }
+            Codec::MessagePack => {
+                // Fields are encoded by name: tagged enums and skipped
+                // fields cannot be decoded from positional arrays
+                let payload = rmp_serde::to_vec_named(value)?;
+                let length = u32::try_from(payload.len())?;
+                buffer.extend_from_slice(&length.to_be_bytes());
+                buffer.extend_from_slice(&payload);
+            }
+        }
+        Ok(())
+    }
+
+    /// Deserialize the payload of one frame, delimiter excluded
+    pub fn decode<T: DeserializeOwned>(self, payload: &[u8]) -> Result<T, DecodeError> {
+        match self {
+            Codec::Json => serde_json::from_slice(payload).map_err(DecodeError::Json),
+            Codec::MessagePack => rmp_serde::from_slice(payload).map_err(DecodeError::MessagePack),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Json(serde_json::Error),
+    MessagePack(rmp_serde::decode::Error),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Json(e) => write!(f, "{}", e),
+            DecodeError::MessagePack(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DecodeError::Json(e) => Some(e),
+            DecodeError::MessagePack(e) => Some(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+
+    use super::*;
+    use crate::command::*;
+    use crate::connection::NEW_LINE;
+    use crate::envelope::Envelope;
+
+    const CODECS: [Codec; 2] = [Codec::Json, Codec::MessagePack];
+
+    /// Encode `envelope` with `codec` then decode the payload of the frame as
+    /// `Out`
+    fn transcode<In, Out>(codec: Codec, envelope: &Envelope<In>) -> Envelope<Out>
+        where In: Serialize, Out: DeserializeOwned
+    {
+        let mut frame = Vec::new();
+        codec.encode(envelope, &mut frame).unwrap();
+        let payload = match codec {
+            Codec::Json => frame.strip_suffix(NEW_LINE).expect("JSON frame without delimiter"),
+            Codec::MessagePack => {
+                let length = u32::from_be_bytes(frame[..4].try_into().unwrap()) as usize;
+                assert_eq!(length, frame.len() - 4);
+                &frame[4..]
+            }
+        };
+        codec.decode(payload).unwrap()
+    }
+
+    fn round_trip<T>(codec: Codec, envelope: &Envelope<T>) -> Envelope<T>
+        where T: Serialize + DeserializeOwned
+    {
+        transcode(codec, envelope)
+    }
+
+    /// Every way an envelope can be sent: request, reply without id and push
+    fn assert_round_trips<T>(commands: Vec<T>)
+        where T: Serialize + DeserializeOwned + PartialEq + Debug + Clone
+    {
+        for codec in CODECS {
+            for command in &commands {
+                for envelope in [
+                    Envelope::request(7, command.clone()),
+                    Envelope::reply(None, command.clone()),
+                    Envelope::push(command.clone()),
+                ] {
+                    assert_eq!(round_trip(codec, &envelope), envelope, "{:?}", codec);
+                }
+            }
+        }
+    }
+
+    fn profile() -> Profile {
+        Profile { login: "alice".to_string() }
+    }
+
+    #[test]
+    fn user_commands_round_trip() {
+        let mut hello = HelloCommand::new("termplay-client", "0.1.0");
+        hello.capabilities = vec![Codec::MessagePack.capability().to_string()];
+        assert_round_trips(
+            vec![
+                UserCommand::Hello(hello),
+                UserCommand::Ping(PingCommand { nonce: u64::MAX }),
+                UserCommand::Pong(PongCommand { nonce: 0 }),
+                UserCommand::Register(RegisterCommand {
+                    login: "alice".to_string(),
+                    email: "alice@example.com".to_string(),
+                    password: "pâssword".to_string(),
+                    locale: None,
+                }),
+                UserCommand::Register(RegisterCommand {
+                    login: "alice".to_string(),
+                    email: "alice@example.com".to_string(),
+                    password: "password".to_string(),
+                    locale: Some("fr".to_string()),
+                }),
+                UserCommand::Login(LoginCommand {
+                    login: "alice".to_string(),
+                    password: "password".to_string(),
+                }),
+                UserCommand::CertificateLogin,
+                UserCommand::Resume(ResumeCommand { session_token: "0123abcd".to_string() }),
+                UserCommand::Logout,
+                UserCommand::JoinLobby,
+                UserCommand::LeaveLobby,
+                UserCommand::JoinGame(JoinGameCommand { game_id: "game-1".to_string() }),
+                UserCommand::LeaveGame,
+            ]
+        );
+    }
+
+    #[test]
+    fn commands_from_a_newer_client_are_unknown() {
+        #[derive(Serialize)]
+        #[serde(tag = "_ct", rename_all = "snake_case")]
+        enum NewerCommand {
+            Spectate {
+                game: u32,
+            },
+        }
+
+        for codec in CODECS {
+            let envelope: Envelope<UserCommand> = transcode(
+                codec,
+                &Envelope::request(7, NewerCommand::Spectate { game: 3 })
+            );
+            assert_eq!(envelope, Envelope::request(7, UserCommand::Unknown), "{:?}", codec);
+        }
+    }
+
+    #[test]
+    fn server_commands_round_trip() {
+        assert_round_trips(
+            vec![
+                ServerCommand::Welcome(WelcomeCommand {
+                    protocol_version: PROTOCOL_VERSION,
+                    server_name: "termplay-register-server".to_string(),
+                    server_version: "0.1.0".to_string(),
+                    capabilities: vec![Codec::Json.capability().to_string()],
+                    codec: Codec::MessagePack,
+                }),
+                ServerCommand::HandshakeRejected(HandshakeRejectedCommand {
+                    min_protocol_version: MIN_PROTOCOL_VERSION,
+                    max_protocol_version: PROTOCOL_VERSION,
+                    reason: "too old".to_string(),
+                }),
+                ServerCommand::Error(ErrorCommand::new(ErrorCode::StorageUnavailable, "try again")),
+                ServerCommand::Error(ErrorCommand::new(ErrorCode::InvalidCredentials, "wrong password")),
+                ServerCommand::Ping(PingCommand { nonce: 42 }),
+                ServerCommand::Pong(PongCommand { nonce: 42 }),
+                ServerCommand::RegisterResponse(RegisterResponseCommand {
+                    email_queued: true,
+                    rejection: None,
+                }),
+                ServerCommand::RegisterResponse(RegisterResponseCommand {
+                    email_queued: false,
+                    rejection: Some(RegisterRejection::LoginTaken),
+                }),
+                ServerCommand::LoginResponse(LoginResponseCommand {
+                    session_token: "0123abcd".to_string(),
+                    expires_at: 1_800_000_000,
+                    profile: profile(),
+                }),
+                ServerCommand::ResumeResponse(ResumeResponseCommand {
+                    expires_at: 1_800_000_000,
+                    profile: profile(),
+                    in_lobby: true,
+                    game_id: None,
+                }),
+                ServerCommand::ResumeResponse(ResumeResponseCommand {
+                    expires_at: 1_800_000_000,
+                    profile: profile(),
+                    in_lobby: true,
+                    game_id: Some("game-1".to_string()),
+                }),
+                ServerCommand::LogoutResponse,
+                ServerCommand::JoinLobbyResponse,
+                ServerCommand::LeaveLobbyResponse,
+                ServerCommand::JoinGameResponse,
+                ServerCommand::LeaveGameResponse,
+            ]
+        );
+    }
+}
@@ -1,24 +1,272 @@
 use serde::{ Deserialize, Serialize };
 
+use crate::codec::Codec;
+
+/// Version of the wire protocol spoken by this build
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version a server built from this crate still accepts
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HelloCommand {
+    pub protocol_version: u32,
+    pub client_name: String,
+    pub client_version: String,
+    pub capabilities: Vec<String>,
+}
+
+impl HelloCommand {
+    pub fn new(client_name: &str, client_version: &str) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            client_name: client_name.to_string(),
+            client_version: client_version.to_string(),
+            capabilities: Vec::new(),
+        }
+    }
+
+    /// Codecs the client listed in its capabilities, in order of preference
+    pub fn offered_codecs(&self) -> Vec<Codec> {
+        self.capabilities
+            .iter()
+            .filter_map(|capability| Codec::from_capability(capability))
+            .collect()
+    }
+}
+
+/// Keepalive probe, either side may send one at any time
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PingCommand {
+    pub nonce: u64,
+}
+
+/// Answer to the [PingCommand] with the same nonce
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PongCommand {
+    pub nonce: u64,
+}
+
+/// Longest email address a server accepts, the most SMTP can deliver to
+pub const MAX_EMAIL_LENGTH: usize = 254;
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RegisterCommand {
+    pub login: String,
+    /// Where the confirmation email is sent
+    pub email: String,
+    pub password: String,
+    /// Language of the confirmation email, e.g. `fr`, the server picks when
+    /// missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+}
+
+impl RegisterCommand {
+    /// Language of the user's environment, from `LC_ALL`, `LC_MESSAGES` or
+    /// `LANG`, such as `fr` for `fr_FR.UTF-8`
+    pub fn system_locale() -> Option<String> {
+        ["LC_ALL", "LC_MESSAGES", "LANG"]
+            .iter()
+            .filter_map(|name| std::env::var(name).ok())
+            .find(|value| !value.is_empty())
+            .and_then(|value| value.split(['_', '.', '@']).next().map(str::to_lowercase))
+            .filter(|language| !language.is_empty() && language != "c" && language != "posix")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoginCommand {
     pub login: String,
     pub password: String,
 }
 
+/// Reattach a new connection to a session opened by an earlier login
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResumeCommand {
+    pub session_token: String,
+}
+
+/// Enter a game from the lobby
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JoinGameCommand {
+    pub game_id: String,
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "_ct", rename_all = "snake_case")]
 pub enum UserCommand {
+    Hello(HelloCommand),
+    Ping(PingCommand),
+    Pong(PongCommand),
     Register(RegisterCommand),
+    Login(LoginCommand),
+    /// Log in as the account named by the verified client certificate
+    CertificateLogin,
+    Resume(ResumeCommand),
+    /// Close the session, its token cannot be resumed afterwards
+    Logout,
+    JoinLobby,
+    LeaveLobby,
+    JoinGame(JoinGameCommand),
+    /// Go back to the lobby
+    LeaveGame,
+    /// A command introduced by a newer client, answered with
+    /// [ErrorCode::UnknownCommand]
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WelcomeCommand {
+    pub protocol_version: u32,
+    pub server_name: String,
+    pub server_version: String,
+    pub capabilities: Vec<String>,
+    /// Codec both sides use for every frame after this one
+    #[serde(default)]
+    pub codec: Codec,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HandshakeRejectedCommand {
+    pub min_protocol_version: u32,
+    pub max_protocol_version: u32,
+    pub reason: String,
+}
+
+/// Stable identifiers for the failures a server reports. Clients should
+/// match on these rather than on the human readable message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request frame was too large or could not be decoded
+    InvalidFrame,
+    /// The server does not handle this command
+    UnknownCommand,
+    /// The command is known but not valid at this point of the conversation
+    UnexpectedCommand,
+    /// The server could not read or write its database
+    StorageUnavailable,
+    /// The login or the password is wrong
+    InvalidCredentials,
+    /// The session token is unknown or expired, the user must log in again
+    InvalidSession,
+    /// The email address of the account has not been confirmed yet
+    AccountNotConfirmed,
+    AccountSuspended,
+    AccountDeleted,
+    /// Any other server side failure
+    Internal,
+    /// A code introduced by a newer server
+    #[serde(other)]
+    Unknown,
+}
+
+impl ErrorCode {
+    /// Whether sending the same request again later may succeed
+    pub fn is_retryable(self) -> bool {
+        matches!(self, ErrorCode::StorageUnavailable)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorCommand {
+    pub code: ErrorCode,
+    pub message: String,
+    pub retryable: bool,
+}
+
+impl ErrorCommand {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            retryable: code.is_retryable(),
+        }
+    }
+}
+
+/// Why a registration was refused, the account was not created
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegisterRejection {
+    /// Another account already uses this login, whatever its case
+    LoginTaken,
+    /// Another account already uses this email address, whatever its case
+    EmailTaken,
+    InvalidEmail,
+    /// The login is empty or only made of spaces
+    InvalidLogin,
+    EmptyPassword,
+    /// A reason introduced by a newer server
+    #[serde(other)]
+    Unknown,
+}
+
+impl RegisterRejection {
+    pub fn message(self) -> &'static str {
+        match self {
+            RegisterRejection::LoginTaken => "This login is already taken",
+            RegisterRejection::EmailTaken => "This email address is already registered",
+            RegisterRejection::InvalidEmail => "This email address is not valid",
+            RegisterRejection::InvalidLogin => "The login cannot be blank",
+            RegisterRejection::EmptyPassword => "The password cannot be empty",
+            RegisterRejection::Unknown => "The registration was refused",
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RegisterResponseCommand {
-    pub email_sent: bool,
+    /// The confirmation email is on its way, it is delivered in the background
+    #[serde(alias = "email_sent")]
+    pub email_queued: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rejection: Option<RegisterRejection>,
+}
+
+/// What the server knows about the logged in user
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    pub login: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoginResponseCommand {
+    /// Opaque token identifying the session on later connections
+    pub session_token: String,
+    /// When the session stops being valid, in seconds since the Unix epoch
+    pub expires_at: u64,
+    pub profile: Profile,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResumeResponseCommand {
+    pub expires_at: u64,
+    pub profile: Profile,
+    /// Whether the session was in the lobby when its last connection dropped
+    #[serde(default)]
+    pub in_lobby: bool,
+    /// Game the session was in when its last connection dropped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub game_id: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "_st", rename_all = "snake_case")]
 pub enum ServerCommand {
+    Welcome(WelcomeCommand),
+    HandshakeRejected(HandshakeRejectedCommand),
+    Error(ErrorCommand),
+    Ping(PingCommand),
+    Pong(PongCommand),
     RegisterResponse(RegisterResponseCommand),
+    LoginResponse(LoginResponseCommand),
+    ResumeResponse(ResumeResponseCommand),
+    LogoutResponse,
+    JoinLobbyResponse,
+    LeaveLobbyResponse,
+    JoinGameResponse,
+    LeaveGameResponse,
 }
@@ -0,0 +1,515 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use serde::{ de::DeserializeOwned, Serialize };
+use tokio::io::{ AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt };
+use tokio::time::{ sleep_until, Instant };
+
+use crate::codec::{ Codec, DecodeError };
+use crate::heartbeat::{ Heartbeat, Probe };
+
+/// Terminator appended to every serialized frame
+pub const NEW_LINE: &[u8; 2] = b"\r\n";
+
+/// Default upper bound for a single frame, delimiter included
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = 64 * 1024;
+
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(45);
+
+const READ_CHUNK_LENGTH: usize = 4096;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionConfig {
+    /// Frames longer than this are discarded without being buffered whole
+    pub max_frame_length: usize,
+    /// Ping the peer this often while receiving, `None` to never ping
+    pub keepalive_interval: Option<Duration>,
+    /// Give up on the peer after this long without receiving any frame,
+    /// `None` to wait forever
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+            keepalive_interval: Some(DEFAULT_KEEPALIVE_INTERVAL),
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+        }
+    }
+}
+
+/// Why [Connection::receive] did not produce a command
+#[derive(Debug)]
+pub enum ReceiveError {
+    /// The peer closed the stream between two frames
+    Closed,
+    /// A frame went over [ConnectionConfig::max_frame_length]. It is skipped
+    /// and the connection stays usable.
+    FrameTooLarge {
+        limit: usize,
+    },
+    /// A complete frame could not be decoded. It is skipped and the
+    /// connection stays usable.
+    Malformed(DecodeError),
+    /// Nothing was received for [ConnectionConfig::idle_timeout], the peer
+    /// is presumed dead
+    IdleTimeout(Duration),
+    /// The stream failed, or was closed in the middle of a frame
+    Io(std::io::Error),
+}
+
+impl ReceiveError {
+    /// Whether the failure only concerns one frame, the following ones can
+    /// still be read
+    pub fn is_bad_frame(&self) -> bool {
+        matches!(self, ReceiveError::FrameTooLarge { .. } | ReceiveError::Malformed(_))
+    }
+}
+
+impl fmt::Display for ReceiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReceiveError::Closed => write!(f, "Connection closed by peer"),
+            ReceiveError::FrameTooLarge { limit } => {
+                write!(f, "Frame exceeds the maximum length of {} bytes", limit)
+            }
+            ReceiveError::Malformed(e) => write!(f, "Error deserializing command: {}", e),
+            ReceiveError::IdleTimeout(timeout) => {
+                write!(f, "Nothing received from peer for {} seconds", timeout.as_secs())
+            }
+            ReceiveError::Io(e) => write!(f, "Error reading from connection: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReceiveError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReceiveError::Malformed(e) => Some(e),
+            ReceiveError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ReceiveError {
+    fn from(e: std::io::Error) -> Self {
+        ReceiveError::Io(e)
+    }
+}
+
+/// Bytes still to be skipped from an oversized frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Discard {
+    Nothing,
+    UntilNewLine,
+    Bytes(usize),
+}
+
+/// A framed command channel over any byte stream.
+///
+/// Outgoing `Out` values are encoded with the current [Codec] and incoming
+/// frames are decoded as `In`, see [Codec] for the framing. Both directions
+/// keep their pending bytes inside the connection rather than on the stack of
+/// the calling future, which is what makes [Connection::send] and
+/// [Connection::receive] usable as [tokio::select!] branches.
+///
+/// While receiving, the connection also keeps the link alive: it answers the
+/// peer's pings, pings the peer every [ConnectionConfig::keepalive_interval]
+/// to measure the round trip time, and gives up after
+/// [ConnectionConfig::idle_timeout] of silence.
+pub struct Connection<In, Out, S> {
+    stream: S,
+    config: ConnectionConfig,
+    codec: Codec,
+    read_buffer: Vec<u8>,
+    discard: Discard,
+    write_buffer: Vec<u8>,
+    last_received: Instant,
+    next_ping: Instant,
+    last_nonce: u64,
+    /// Nonce and send time of the ping waiting for its pong
+    ping_in_flight: Option<(u64, Instant)>,
+    round_trip_time: Option<Duration>,
+    _commands: PhantomData<fn(Out) -> In>,
+}
+
+impl<In, Out, S> Connection<In, Out, S>
+    where
+        In: DeserializeOwned + Heartbeat,
+        Out: Serialize + Heartbeat,
+        S: AsyncRead + AsyncWrite + Unpin
+{
+    pub fn new(stream: S) -> Self {
+        Self::with_config(stream, ConnectionConfig::default())
+    }
+
+    pub fn with_config(stream: S, config: ConnectionConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            stream,
+            config,
+            codec: Codec::default(),
+            read_buffer: Vec::new(),
+            discard: Discard::Nothing,
+            write_buffer: Vec::new(),
+            last_received: now,
+            next_ping: now + config.keepalive_interval.unwrap_or_default(),
+            last_nonce: 0,
+            ping_in_flight: None,
+            round_trip_time: None,
+            _commands: PhantomData,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Round trip time measured by the last answered ping
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        self.round_trip_time
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Switch both directions to `codec`.
+    ///
+    /// Frames already queued for writing keep the encoding they were fed
+    /// with, frames received from now on are decoded with `codec`.
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    /// Serialize `command` into the outgoing buffer without writing it.
+    ///
+    /// The frame is written by the next call to [Connection::flush] or
+    /// [Connection::send].
+    pub fn feed(&mut self, command: &Out) -> anyhow::Result<()> {
+        self.codec.encode(command, &mut self.write_buffer)
+    }
+
+    /// Write every queued frame to the backing stream.
+    ///
+    /// # Cancel Safety
+    ///
+    /// This method is cancellation safe. Bytes are only removed from the
+    /// outgoing buffer once the stream accepted them, so a cancelled flush
+    /// resumes exactly where it stopped on the next call.
+    pub async fn flush(&mut self) -> anyhow::Result<()> {
+        while !self.write_buffer.is_empty() {
+            let written = self.stream.write(&self.write_buffer).await?;
+            if written == 0 {
+                anyhow::bail!("Connection closed while writing");
+            }
+            self.write_buffer.drain(..written);
+        }
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// Send a command to the peer.
+    ///
+    /// # Cancel Safety
+    ///
+    /// This method is cancellation safe. The whole frame is queued before
+    /// anything is written; if the future is dropped part way, the rest of
+    /// the frame stays queued and goes out with the next [Connection::send]
+    /// or [Connection::flush]. A frame is never interleaved with another one.
+    pub async fn send(&mut self, command: &Out) -> anyhow::Result<()> {
+        self.feed(command)?;
+        self.flush().await
+    }
+
+    /// Receive the next command from the peer.
+    ///
+    /// Pings and pongs are handled here and never returned. Never buffers
+    /// more than [ConnectionConfig::max_frame_length] bytes plus one read
+    /// chunk, whatever the peer sends.
+    ///
+    /// # Cancel Safety
+    ///
+    /// This method is cancellation safe. Partially received frames are kept
+    /// in the connection and completed by the next call, and keepalive
+    /// deadlines live in the connection rather than in the future.
+    pub async fn receive(&mut self) -> Result<In, ReceiveError> {
+        loop {
+            if let Some(frame) = self.next_frame()? {
+                self.last_received = Instant::now();
+                let command: In = self.codec.decode(&frame).map_err(ReceiveError::Malformed)?;
+                match command.probe() {
+                    Some(Probe::Ping(nonce)) => {
+                        self.feed(&Out::pong(nonce)).map_err(into_io_error)?;
+                        self.flush().await.map_err(into_io_error)?;
+                    }
+                    Some(Probe::Pong(nonce)) => {
+                        if let Some((expected, sent_at)) = self.ping_in_flight {
+                            if expected == nonce {
+                                self.round_trip_time = Some(sent_at.elapsed());
+                                self.ping_in_flight = None;
+                            }
+                        }
+                    }
+                    None => {
+                        return Ok(command);
+                    }
+                }
+                continue;
+            }
+
+            let idle_deadline = self.config.idle_timeout.map(|timeout| self.last_received + timeout);
+            let mut chunk = [0u8; READ_CHUNK_LENGTH];
+            let read = tokio::select! {
+                read = self.stream.read(&mut chunk) => read?,
+                _ = sleep_until(self.next_ping), if self.config.keepalive_interval.is_some() => {
+                    self.ping().await?;
+                    continue;
+                },
+                _ = sleep_until(idle_deadline.unwrap_or(self.last_received)), if idle_deadline.is_some() => {
+                    return Err(ReceiveError::IdleTimeout(self.config.idle_timeout.unwrap_or_default()));
+                },
+            };
+            if read == 0 {
+                if self.read_buffer.is_empty() && self.discard == Discard::Nothing {
+                    return Err(ReceiveError::Closed);
+                }
+                return Err(
+                    ReceiveError::Io(
+                        std::io::Error::new(
+                            std::io::ErrorKind::UnexpectedEof,
+                            "connection closed in the middle of a frame"
+                        )
+                    )
+                );
+            }
+            self.read_buffer.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    /// Ping the peer, unless the last ping is still unanswered: its round
+    /// trip is the one measured, and the idle timeout deals with a peer that
+    /// never answers
+    async fn ping(&mut self) -> Result<(), ReceiveError> {
+        let now = Instant::now();
+        self.next_ping = now + self.config.keepalive_interval.unwrap_or_default();
+        if self.ping_in_flight.is_some() {
+            return Ok(());
+        }
+        self.last_nonce += 1;
+        self.ping_in_flight = Some((self.last_nonce, now));
+        self.feed(&Out::ping(self.last_nonce)).map_err(into_io_error)?;
+        self.flush().await.map_err(into_io_error)
+    }
+
+    /// Split the payload of the next complete frame off the read buffer,
+    /// enforcing the length limit
+    fn next_frame(&mut self) -> Result<Option<Vec<u8>>, ReceiveError> {
+        let limit = self.config.max_frame_length;
+
+        // Skip what is left of a frame already reported as too large
+        match self.discard {
+            Discard::Nothing => {}
+            Discard::UntilNewLine => {
+                match self.read_buffer.iter().position(|byte| *byte == b'\n') {
+                    Some(end) => {
+                        self.read_buffer.drain(..=end);
+                    }
+                    None => {
+                        self.read_buffer.clear();
+                        return Ok(None);
+                    }
+                }
+            }
+            Discard::Bytes(remaining) => {
+                let skipped = remaining.min(self.read_buffer.len());
+                self.read_buffer.drain(..skipped);
+                if skipped < remaining {
+                    self.discard = Discard::Bytes(remaining - skipped);
+                    return Ok(None);
+                }
+            }
+        }
+        self.discard = Discard::Nothing;
+
+        match self.codec {
+            Codec::Json => {
+                match self.read_buffer.iter().position(|byte| *byte == b'\n') {
+                    Some(end) => {
+                        let frame: Vec<u8> = self.read_buffer.drain(..=end).collect();
+                        if frame.len() > limit {
+                            return Err(ReceiveError::FrameTooLarge { limit });
+                        }
+                        Ok(Some(frame))
+                    }
+                    None if self.read_buffer.len() > limit => {
+                        self.read_buffer.clear();
+                        self.discard = Discard::UntilNewLine;
+                        Err(ReceiveError::FrameTooLarge { limit })
+                    }
+                    None => Ok(None),
+                }
+            }
+            Codec::MessagePack => {
+                let Some(prefix) = self.read_buffer.get(..4) else {
+                    return Ok(None);
+                };
+                let length = u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]);
+                let length = length as usize;
+                if length + 4 > limit {
+                    self.read_buffer.drain(..4);
+                    self.discard = Discard::Bytes(length);
+                    return Err(ReceiveError::FrameTooLarge { limit });
+                }
+                if self.read_buffer.len() < length + 4 {
+                    return Ok(None);
+                }
+                self.read_buffer.drain(..4);
+                Ok(Some(self.read_buffer.drain(..length).collect()))
+            }
+        }
+    }
+}
+
+fn into_io_error(e: anyhow::Error) -> ReceiveError {
+    match e.downcast::<std::io::Error>() {
+        Ok(e) => ReceiveError::Io(e),
+        Err(e) => ReceiveError::Io(std::io::Error::other(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{ duplex, AsyncWriteExt, DuplexStream };
+
+    use super::*;
+    use crate::command::{ LoginCommand, PongCommand, ServerCommand, UserCommand };
+
+    const LIMIT: usize = 256;
+
+    /// Server side of a connection whose client end is written by hand
+    fn server(codec: Codec) -> (Connection<UserCommand, ServerCommand, DuplexStream>, DuplexStream) {
+        // Smaller than a frame, so frames arrive over several reads
+        let (client, server) = duplex(LIMIT / 4);
+        let config = ConnectionConfig {
+            max_frame_length: LIMIT,
+            keepalive_interval: None,
+            idle_timeout: None,
+        };
+        let mut connection = Connection::with_config(server, config);
+        connection.set_codec(codec);
+        (connection, client)
+    }
+
+    fn login(password_length: usize) -> UserCommand {
+        UserCommand::Login(LoginCommand {
+            login: "alice".to_string(),
+            password: "x".repeat(password_length),
+        })
+    }
+
+    fn frame(codec: Codec, command: &UserCommand) -> Vec<u8> {
+        let mut frame = Vec::new();
+        codec.encode(command, &mut frame).unwrap();
+        frame
+    }
+
+    /// An oversized frame is reported then skipped, the next one still reads.
+    /// The frame spans several read chunks so it is never buffered whole.
+    async fn assert_skips_oversized_frame(codec: Codec) {
+        let (mut connection, mut client) = server(codec);
+        let oversized = frame(codec, &login(2 * READ_CHUNK_LENGTH));
+        let good = frame(codec, &login(8));
+        tokio::spawn(async move {
+            client.write_all(&oversized).await.unwrap();
+            client.write_all(&good).await.unwrap();
+        });
+
+        match connection.receive().await {
+            Err(ReceiveError::FrameTooLarge { limit }) => assert_eq!(limit, LIMIT),
+            other => panic!("expected FrameTooLarge, got {:?}", other),
+        }
+        assert_eq!(connection.receive().await.unwrap(), login(8));
+    }
+
+    #[tokio::test]
+    async fn oversized_json_frame_is_skipped() {
+        assert_skips_oversized_frame(Codec::Json).await;
+    }
+
+    #[tokio::test]
+    async fn oversized_message_pack_frame_is_skipped() {
+        assert_skips_oversized_frame(Codec::MessagePack).await;
+    }
+
+    #[tokio::test]
+    async fn eof_between_frames_is_closed() {
+        for codec in [Codec::Json, Codec::MessagePack] {
+            let (mut connection, mut client) = server(codec);
+            let frame = frame(codec, &login(8));
+            tokio::spawn(async move { client.write_all(&frame).await.unwrap() });
+
+            assert_eq!(connection.receive().await.unwrap(), login(8));
+            match connection.receive().await {
+                Err(ReceiveError::Closed) => {}
+                other => panic!("expected Closed with {:?}, got {:?}", codec, other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn eof_inside_a_frame_is_unexpected() {
+        for codec in [Codec::Json, Codec::MessagePack] {
+            let (mut connection, mut client) = server(codec);
+            let frame = frame(codec, &login(8));
+            tokio::spawn(async move { client.write_all(&frame[..frame.len() / 2]).await.unwrap() });
+
+            match connection.receive().await {
+                Err(ReceiveError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof),
+                other => panic!("expected UnexpectedEof with {:?}, got {:?}", codec, other),
+            }
+        }
+    }
+
+    /// A peer slower to answer than the keepalive interval gets one ping at
+    /// a time, and its late pong still measures the round trip
+    #[tokio::test]
+    async fn waits_for_the_pong_before_pinging_again() {
+        let (client, server) = duplex(4096);
+        let config = ConnectionConfig {
+            max_frame_length: LIMIT,
+            keepalive_interval: Some(Duration::from_millis(10)),
+            idle_timeout: None,
+        };
+        let mut connection: Connection<UserCommand, ServerCommand, DuplexStream> = Connection::with_config(
+            server,
+            config
+        );
+        connection.set_codec(Codec::Json);
+        let (mut client_reader, mut client_writer) = tokio::io::split(client);
+        let answer = frame(Codec::Json, &UserCommand::Pong(PongCommand { nonce: 1 }));
+        let next = frame(Codec::Json, &login(8));
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            client_writer.write_all(&answer).await.unwrap();
+            client_writer.write_all(&next).await.unwrap();
+        });
+
+        assert_eq!(connection.receive().await.unwrap(), login(8));
+        // Sent one interval after the connection was made
+        assert!(connection.round_trip_time().unwrap() >= Duration::from_millis(80));
+        drop(connection);
+        let mut sent = String::new();
+        tokio::io::AsyncReadExt::read_to_string(&mut client_reader, &mut sent).await.unwrap();
+        assert_eq!(sent.matches("\"ping\"").count(), 1, "sent {}", sent);
+    }
+}
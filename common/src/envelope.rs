@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+
+use serde::{ Deserialize, Serialize };
+
+/// Identifier a client gives to a request, echoed back in the reply
+pub type RequestId = u64;
+
+/// Request id of the opening hello, sent before any other request exists
+pub const HANDSHAKE_REQUEST_ID: RequestId = 0;
+
+/// Wrapper around every command on the wire.
+///
+/// Requests carry the id chosen by the client and replies carry the id of the
+/// request they answer. Messages the server sends on its own initiative have
+/// no id and are flagged as `push`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<RequestId>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub push: bool,
+    pub command: T,
+}
+
+impl<T> Envelope<T> {
+    pub fn request(id: RequestId, command: T) -> Self {
+        Self { id: Some(id), push: false, command }
+    }
+
+    /// Answer to the request `id`. `None` when the request could not be
+    /// identified, e.g. because its frame was unreadable.
+    pub fn reply(id: Option<RequestId>, command: T) -> Self {
+        Self { id, push: false, command }
+    }
+
+    pub fn push(command: T) -> Self {
+        Self { id: None, push: true, command }
+    }
+}
+
+/// Requests sent to the peer that are still waiting for their reply, each
+/// with whatever context `T` is needed to handle that reply.
+#[derive(Debug)]
+pub struct PendingRequests<T> {
+    last_id: RequestId,
+    pending: HashMap<RequestId, T>,
+}
+
+impl<T> PendingRequests<T> {
+    pub fn new() -> Self {
+        Self {
+            last_id: HANDSHAKE_REQUEST_ID,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Allocate an id for a new request and keep `context` until its reply
+    pub fn register(&mut self, context: T) -> RequestId {
+        self.last_id += 1;
+        self.pending.insert(self.last_id, context);
+        self.last_id
+    }
+
+    /// Take back the context of the request answered by a reply with `id`
+    pub fn resolve(&mut self, id: RequestId) -> Option<T> {
+        self.pending.remove(&id)
+    }
+
+    /// Forget every pending request, e.g. when the connection is lost
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+impl<T> Default for PendingRequests<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,68 @@
+use crate::command::{ PingCommand, PongCommand, ServerCommand, UserCommand };
+use crate::envelope::Envelope;
+
+/// Keepalive frame recognised by a [crate::connection::Connection]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Probe {
+    Ping(u64),
+    Pong(u64),
+}
+
+/// Commands able to carry the keepalive probes a
+/// [crate::connection::Connection] answers and measures on its own.
+pub trait Heartbeat: Sized {
+    fn ping(nonce: u64) -> Self;
+    fn pong(nonce: u64) -> Self;
+    fn probe(&self) -> Option<Probe>;
+}
+
+impl Heartbeat for UserCommand {
+    fn ping(nonce: u64) -> Self {
+        UserCommand::Ping(PingCommand { nonce })
+    }
+
+    fn pong(nonce: u64) -> Self {
+        UserCommand::Pong(PongCommand { nonce })
+    }
+
+    fn probe(&self) -> Option<Probe> {
+        match self {
+            UserCommand::Ping(PingCommand { nonce }) => Some(Probe::Ping(*nonce)),
+            UserCommand::Pong(PongCommand { nonce }) => Some(Probe::Pong(*nonce)),
+            _ => None,
+        }
+    }
+}
+
+impl Heartbeat for ServerCommand {
+    fn ping(nonce: u64) -> Self {
+        ServerCommand::Ping(PingCommand { nonce })
+    }
+
+    fn pong(nonce: u64) -> Self {
+        ServerCommand::Pong(PongCommand { nonce })
+    }
+
+    fn probe(&self) -> Option<Probe> {
+        match self {
+            ServerCommand::Ping(PingCommand { nonce }) => Some(Probe::Ping(*nonce)),
+            ServerCommand::Pong(PongCommand { nonce }) => Some(Probe::Pong(*nonce)),
+            _ => None,
+        }
+    }
+}
+
+/// Probes are not answers to a request, they travel as push messages
+impl<T: Heartbeat> Heartbeat for Envelope<T> {
+    fn ping(nonce: u64) -> Self {
+        Envelope::push(T::ping(nonce))
+    }
+
+    fn pong(nonce: u64) -> Self {
+        Envelope::push(T::pong(nonce))
+    }
+
+    fn probe(&self) -> Option<Probe> {
+        self.command.probe()
+    }
+}
@@ -1,3 +1,10 @@
+pub mod codec;
 pub mod command;
+pub mod connection;
+pub mod envelope;
+pub mod heartbeat;
+pub mod html;
 pub mod client;
 pub mod server;
+pub mod transport;
+pub mod trust;
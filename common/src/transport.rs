@@ -0,0 +1,372 @@
+use std::fmt;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use tokio::io::{ AsyncRead, AsyncWrite };
+use tokio::net::{ TcpListener, TcpStream };
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use anyhow::Context;
+use tokio_native_tls::{ native_tls, TlsConnector };
+
+use crate::trust::TrustConfig;
+
+/// Port used when an endpoint does not name one
+pub const DEFAULT_PORT: u16 = 8080;
+
+/// Any byte stream a [Connection](crate::connection::Connection) can run on
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T> Stream for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+pub type BoxedStream = Box<dyn Stream>;
+
+/// Where a server listens or a client connects, written as
+/// `tls://host:port`, `tcp://host:port` or `unix:///path/to/socket`.
+///
+/// A bare `host:port` means TLS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    Tls {
+        host: String,
+        port: u16,
+    },
+    /// Unencrypted TCP, only meant for local development
+    Tcp {
+        host: String,
+        port: u16,
+    },
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    pub fn is_tls(&self) -> bool {
+        matches!(self, Endpoint::Tls { .. })
+    }
+
+    /// Refuse plain TCP unless it was explicitly allowed
+    pub fn check_plaintext(&self, allow_plaintext: bool) -> anyhow::Result<()> {
+        if matches!(self, Endpoint::Tcp { .. }) && !allow_plaintext {
+            anyhow::bail!(
+                "{} is not encrypted, plain TCP must be explicitly allowed and only used for local development",
+                self
+            );
+        }
+        Ok(())
+    }
+}
+
+fn parse_host_port(address: &str) -> anyhow::Result<(String, u16)> {
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) => {
+            let port = port
+                .parse()
+                .map_err(|e| anyhow::anyhow!("Invalid port '{}': {}", port, e))?;
+            (host, port)
+        }
+        None => (address, DEFAULT_PORT),
+    };
+    if host.is_empty() {
+        anyhow::bail!("Missing host in '{}'", address);
+    }
+    Ok((host.trim_start_matches('[').trim_end_matches(']').to_string(), port))
+}
+
+impl FromStr for Endpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, address) = s.split_once("://").unwrap_or(("tls", s));
+        match scheme {
+            "tls" => {
+                let (host, port) = parse_host_port(address)?;
+                Ok(Endpoint::Tls { host, port })
+            }
+            "tcp" => {
+                let (host, port) = parse_host_port(address)?;
+                Ok(Endpoint::Tcp { host, port })
+            }
+            #[cfg(unix)]
+            "unix" => {
+                if address.is_empty() {
+                    anyhow::bail!("Missing socket path in '{}'", s);
+                }
+                Ok(Endpoint::Unix(PathBuf::from(address)))
+            }
+            _ => anyhow::bail!("Unsupported transport '{}' in '{}'", scheme, s),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tls { host, port } => write!(f, "tls://{}:{}", host, port),
+            Endpoint::Tcp { host, port } => write!(f, "tcp://{}:{}", host, port),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+/// Opens client streams to an [Endpoint]
+pub struct Connector {
+    tls: TlsConnector,
+    trust: TrustConfig,
+    allow_plaintext: bool,
+}
+
+impl Connector {
+    /// `identity` is the client certificate, for servers asking for one
+    pub fn new(trust: TrustConfig, identity: Option<native_tls::Identity>) -> anyhow::Result<Self> {
+        // Certificates are not verified on first use, so a CA file would be
+        // ignored, and pins already decide which key is accepted
+        anyhow::ensure!(
+            trust.known_hosts.is_none() || (trust.ca_file.is_none() && trust.pins.is_empty()),
+            "Trusting on first use cannot be combined with a CA file or pins"
+        );
+        let mut builder = native_tls::TlsConnector::builder();
+        if let Some(ca_file) = &trust.ca_file {
+            for certificate in read_certificates(ca_file)? {
+                builder.add_root_certificate(certificate);
+            }
+        }
+        if trust.known_hosts.is_some() {
+            // Trusting on first use, the key is checked after the handshake
+            builder.danger_accept_invalid_certs(true);
+            builder.danger_accept_invalid_hostnames(true);
+        }
+        if let Some(identity) = identity {
+            builder.identity(identity);
+        }
+        Ok(Self {
+            tls: TlsConnector::from(builder.build()?),
+            trust,
+            allow_plaintext: false,
+        })
+    }
+
+    /// Allow `tcp://` endpoints, for local development only
+    pub fn allow_plaintext(mut self, allow_plaintext: bool) -> Self {
+        self.allow_plaintext = allow_plaintext;
+        self
+    }
+
+    pub async fn connect(&self, endpoint: &Endpoint) -> anyhow::Result<BoxedStream> {
+        endpoint.check_plaintext(self.allow_plaintext)?;
+        match endpoint {
+            Endpoint::Tls { host, port } => {
+                let socket = TcpStream::connect((host.as_str(), *port)).await?;
+                let stream = self.tls.connect(host, socket).await?;
+                if self.trust.checks_key() {
+                    let certificate = stream
+                        .get_ref()
+                        .peer_certificate()?
+                        .context("The server did not present a certificate")?;
+                    self.trust.check_key(&format!("{}:{}", host, port), &certificate.to_der()?)?;
+                }
+                Ok(Box::new(stream))
+            }
+            Endpoint::Tcp { host, port } => {
+                Ok(Box::new(TcpStream::connect((host.as_str(), *port)).await?))
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(path) => Ok(Box::new(tokio::net::UnixStream::connect(path).await?)),
+        }
+    }
+}
+
+/// Every certificate of a PEM file
+fn read_certificates(path: &std::path::Path) -> anyhow::Result<Vec<native_tls::Certificate>> {
+    let content = std::fs
+        ::read_to_string(path)
+        .with_context(|| format!("Could not read CA file '{}'", path.display()))?;
+    let certificates = content
+        .split_inclusive("-----END CERTIFICATE-----")
+        .filter(|block| block.contains("-----BEGIN CERTIFICATE-----"))
+        .map(|block| native_tls::Certificate::from_pem(block.trim().as_bytes()))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid certificate in CA file '{}'", path.display()))?;
+    if certificates.is_empty() {
+        anyhow::bail!("CA file '{}' does not contain any PEM certificate", path.display());
+    }
+    Ok(certificates)
+}
+
+/// Accepts server side streams on an [Endpoint].
+///
+/// Streams are handed out as they come off the socket, TLS endpoints still
+/// need their handshake done by the caller.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// Remove the socket file a previous run left behind, which would make bind
+/// fail. Only done when nothing answers on it, and never to another kind of
+/// file.
+#[cfg(unix)]
+async fn remove_stale_socket(path: &std::path::Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if !metadata.file_type().is_socket() {
+        anyhow::bail!("'{}' already exists and is not a socket", path.display());
+    }
+    match tokio::net::UnixStream::connect(path).await {
+        Ok(_) => anyhow::bail!("'{}' is already used by a running server", path.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+            std::fs::remove_file(path).with_context(|| {
+                format!("Could not remove the stale socket '{}'", path.display())
+            })
+        }
+        Err(e) => Err(e).with_context(|| format!("Could not check the socket '{}'", path.display())),
+    }
+}
+
+impl Listener {
+    pub async fn bind(endpoint: &Endpoint, allow_plaintext: bool) -> anyhow::Result<Self> {
+        endpoint.check_plaintext(allow_plaintext)?;
+        match endpoint {
+            Endpoint::Tls { host, port } | Endpoint::Tcp { host, port } => {
+                Ok(Listener::Tcp(TcpListener::bind((host.as_str(), *port)).await?))
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                remove_stale_socket(path).await?;
+                Ok(Listener::Unix(UnixListener::bind(path)?))
+            }
+        }
+    }
+
+    /// Wait for the next client, returning its stream and a printable address
+    pub async fn accept(&self) -> std::io::Result<(BoxedStream, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (socket, addr) = listener.accept().await?;
+                Ok((Box::new(socket), addr.to_string()))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (socket, _) = listener.accept().await?;
+                Ok((Box::new(socket), String::from("unix socket")))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn parse(s: &str) -> anyhow::Result<Endpoint> {
+        s.parse()
+    }
+
+    #[test]
+    fn parses_endpoints() {
+        let tls = Endpoint::Tls { host: "example.com".to_string(), port: 9000 };
+        assert_eq!(parse("tls://example.com:9000").unwrap(), tls);
+        assert_eq!(parse("example.com:9000").unwrap(), tls);
+        assert_eq!(
+            parse("tcp://127.0.0.1:9000").unwrap(),
+            Endpoint::Tcp { host: "127.0.0.1".to_string(), port: 9000 }
+        );
+        assert_eq!(
+            parse("tls://[::1]:9000").unwrap(),
+            Endpoint::Tls { host: "::1".to_string(), port: 9000 }
+        );
+        assert_eq!(
+            parse("example.com").unwrap(),
+            Endpoint::Tls { host: "example.com".to_string(), port: DEFAULT_PORT }
+        );
+        #[cfg(unix)]
+        assert_eq!(
+            parse("unix:///tmp/termplay.sock").unwrap(),
+            Endpoint::Unix(PathBuf::from("/tmp/termplay.sock"))
+        );
+    }
+
+    #[test]
+    fn refuses_invalid_endpoints() {
+        assert!(parse("tls://example.com:").is_err());
+        assert!(parse("tls://example.com:port").is_err());
+        assert!(parse("tls://:9000").is_err());
+        assert!(parse("unix://").is_err());
+        assert!(parse("udp://example.com:9000").is_err());
+    }
+
+    #[test]
+    fn plain_tcp_must_be_allowed() {
+        let tcp = parse("tcp://127.0.0.1:9000").unwrap();
+        assert!(tcp.check_plaintext(false).is_err());
+        assert!(tcp.check_plaintext(true).is_ok());
+        assert!(parse("tls://127.0.0.1:9000").unwrap().check_plaintext(false).is_ok());
+    }
+
+    #[test]
+    fn trust_on_first_use_stands_alone() {
+        let tofu = || TrustConfig {
+            known_hosts: Some(PathBuf::from("known_hosts")),
+            ..TrustConfig::default()
+        };
+        let with_ca_file = TrustConfig { ca_file: Some(PathBuf::from("ca.pem")), ..tofu() };
+        let pin = format!("sha256//{}=", "A".repeat(43)).parse().unwrap();
+        let with_pins = TrustConfig { pins: vec![pin], ..tofu() };
+
+        assert!(Connector::new(tofu(), None).is_ok());
+        for trust in [with_ca_file, with_pins] {
+            let error = Connector::new(trust, None).err().unwrap();
+            assert!(error.to_string().starts_with("Trusting on first use cannot be combined"));
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn round_trips_over_a_unix_socket() {
+        use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+
+        let dir = tempfile::tempdir().unwrap();
+        let endpoint = Endpoint::Unix(dir.path().join("termplay.sock"));
+        let listener = Listener::bind(&endpoint, false).await.unwrap();
+        let connector = Connector::new(TrustConfig::default(), None).unwrap();
+
+        let (client, server) = tokio::join!(connector.connect(&endpoint), listener.accept());
+        let (mut client, mut server) = (client.unwrap(), server.unwrap().0);
+        client.write_all(b"ping").await.unwrap();
+        let mut received = [0; 4];
+        server.read_exact(&mut received).await.unwrap();
+
+        assert_eq!(&received, b"ping");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn only_replaces_stale_sockets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("termplay.sock");
+        let endpoint = Endpoint::Unix(path.clone());
+
+        let listener = Listener::bind(&endpoint, false).await.unwrap();
+        let error = Listener::bind(&endpoint, false).await.err().unwrap();
+        assert!(error.to_string().contains("already used by a running server"));
+
+        // Left behind once the server is gone
+        drop(listener);
+        assert!(path.exists());
+        Listener::bind(&endpoint, false).await.unwrap();
+
+        let file = dir.path().join("notes.txt");
+        std::fs::write(&file, "keep me").unwrap();
+        let error = Listener::bind(&Endpoint::Unix(file.clone()), false).await.err().unwrap();
+        assert!(error.to_string().contains("is not a socket"));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep me");
+    }
+}
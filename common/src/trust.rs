@@ -0,0 +1,224 @@
+use std::fmt;
+use std::fs::{ self, OpenOptions };
+use std::io::Write;
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
+
+use anyhow::Context;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha2::{ Digest, Sha256 };
+use x509_parser::prelude::{ FromDer, X509Certificate };
+
+const PIN_PREFIX: &str = "sha256//";
+
+/// SHA-256 digest of a server public key (its SubjectPublicKeyInfo), written
+/// `sha256//<base64>` like curl's `--pinnedpubkey`.
+///
+/// It can be computed from a certificate with
+/// `openssl x509 -pubkey -noout -in cert.pem | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pin([u8; 32]);
+
+impl Pin {
+    /// Pin of the public key in a DER encoded certificate
+    pub fn of_certificate(der: &[u8]) -> anyhow::Result<Self> {
+        let (_, certificate) = X509Certificate::from_der(der).context(
+            "Could not parse the server certificate"
+        )?;
+        Ok(Pin(Sha256::digest(certificate.public_key().raw).into()))
+    }
+}
+
+impl FromStr for Pin {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let encoded = s
+            .strip_prefix(PIN_PREFIX)
+            .with_context(|| format!("Invalid pin '{}', expected {}<base64>", s, PIN_PREFIX))?;
+        let digest = BASE64.decode(encoded).with_context(|| format!("Invalid pin '{}'", s))?;
+        let digest = digest
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid pin '{}', expected a SHA-256 digest", s))?;
+        Ok(Pin(digest))
+    }
+}
+
+impl fmt::Display for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", PIN_PREFIX, BASE64.encode(self.0))
+    }
+}
+
+/// How a client decides whether to trust the certificate of a server
+#[derive(Debug, Clone, Default)]
+pub struct TrustConfig {
+    /// PEM CA certificates trusted on top of the system ones
+    pub ca_file: Option<PathBuf>,
+    /// When not empty, the server public key must match one of these
+    pub pins: Vec<Pin>,
+    /// Trust on first use. The certificate is not verified, instead the key
+    /// of each server is recorded in this file the first time it is seen and
+    /// must not change afterwards. Cannot be combined with `ca_file` or `pins`.
+    pub known_hosts: Option<PathBuf>,
+}
+
+impl TrustConfig {
+    /// Whether the server key has to be looked at after the handshake
+    pub(crate) fn checks_key(&self) -> bool {
+        !self.pins.is_empty() || self.known_hosts.is_some()
+    }
+
+    /// Check the key of the certificate `host` presented against the pins
+    /// and the known hosts
+    pub(crate) fn check_key(&self, host: &str, certificate: &[u8]) -> anyhow::Result<()> {
+        let pin = Pin::of_certificate(certificate)?;
+        if !self.pins.is_empty() && !self.pins.contains(&pin) {
+            anyhow::bail!("The public key of {} ({}) matches none of the pinned keys", host, pin);
+        }
+        if let Some(path) = &self.known_hosts {
+            KnownHosts::new(path).check(host, &pin)?;
+        }
+        Ok(())
+    }
+}
+
+/// `$XDG_CONFIG_HOME/termplay/known_hosts`, or under `~/.config` by default
+pub fn default_known_hosts_path() -> Option<PathBuf> {
+    let config_dir = std::env
+        ::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_dir.join("termplay").join("known_hosts"))
+}
+
+/// Server keys seen so far, one `host:port sha256//<base64>` line per server
+pub struct KnownHosts<'a> {
+    path: &'a Path,
+}
+
+impl<'a> KnownHosts<'a> {
+    pub fn new(path: &'a Path) -> Self {
+        Self { path }
+    }
+
+    fn lookup(&self, host: &str) -> anyhow::Result<Option<Pin>> {
+        let content = match fs::read_to_string(self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(None);
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("Could not read {}", self.path.display()));
+            }
+        };
+        // Every line is checked, a corrupted file is reported rather than
+        // trusting anew the servers it lost
+        let mut found = None;
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid_line = || format!("Invalid line {} in {}", number + 1, self.path.display());
+            let (known_host, pin) = line.split_once(char::is_whitespace).with_context(invalid_line)?;
+            let pin: Pin = pin.trim().parse().with_context(invalid_line)?;
+            if known_host == host && found.is_none() {
+                found = Some(pin);
+            }
+        }
+        Ok(found)
+    }
+
+    /// Accept `pin` for `host` if it is the one seen before, or remember it if
+    /// `host` is new.
+    pub fn check(&self, host: &str, pin: &Pin) -> anyhow::Result<()> {
+        match self.lookup(host)? {
+            Some(known) if &known == pin => Ok(()),
+            Some(known) => {
+                anyhow::bail!(
+                    "WARNING: THE KEY OF {} HAS CHANGED! Someone could be intercepting the connection. \
+                     Expected {} but the server presented {}. If the server certificate was \
+                     legitimately replaced, remove the line for {} from {}.",
+                    host,
+                    known,
+                    pin,
+                    host,
+                    self.path.display()
+                )
+            }
+            None => self.add(host, pin),
+        }
+    }
+
+    fn add(&self, host: &str, pin: &Pin) -> anyhow::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("Could not create {}", dir.display()))?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path)
+            .with_context(|| format!("Could not open {}", self.path.display()))?;
+        writeln!(file, "{} {}", host, pin).with_context(|| {
+            format!("Could not write to {}", self.path.display())
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: &str = "play.example.com:8080";
+
+    #[test]
+    fn records_an_unknown_host_then_accepts_its_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("termplay").join("known_hosts");
+        let known_hosts = KnownHosts::new(&path);
+        let pin = Pin([1; 32]);
+
+        known_hosts.check(HOST, &pin).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{} {}\n", HOST, pin));
+
+        known_hosts.check(HOST, &pin).unwrap();
+        known_hosts.check("other.example.com:8080", &Pin([2; 32])).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    fn refuses_a_changed_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("known_hosts");
+        let known_hosts = KnownHosts::new(&path);
+        known_hosts.check(HOST, &Pin([1; 32])).unwrap();
+
+        let error = known_hosts.check(HOST, &Pin([2; 32])).unwrap_err();
+
+        assert!(error.to_string().contains(&format!("THE KEY OF {} HAS CHANGED", HOST)));
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+    }
+
+    #[test]
+    fn refuses_a_malformed_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("known_hosts");
+        let pin = Pin([1; 32]);
+        for content in [
+            format!("{}\n", HOST),
+            format!("{} sha256//not-base64\n", HOST),
+            // Even for another host
+            format!("{} {}\nother.example.com:8080 md5//abc\n", HOST, pin),
+        ] {
+            fs::write(&path, &content).unwrap();
+
+            let error = KnownHosts::new(&path).check(HOST, &pin).unwrap_err();
+
+            assert!(error.to_string().starts_with("Invalid line"), "{}: {}", content, error);
+            assert_eq!(fs::read_to_string(&path).unwrap(), content);
+        }
+    }
+}
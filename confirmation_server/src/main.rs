@@ -1,80 +1,119 @@
 #[macro_use]
 extern crate rocket;
 
-use rocket::http::{Cookie, CookieJar, SameSite, Status};
-use rocket::response::status;
-use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
 
-// Structure pour stocker les utilisateurs et leurs états de confirmation
-#[derive(Debug, PartialEq, Eq)]
-enum AccountStatus {
-    Unconfirmed,
-    Confirmed,
-}
+use database::account::{ AccountStatus, TransitionError };
+use database::confirmation::{ self, ConfirmError };
+use rocket::http::{ Cookie, CookieJar, SameSite, Status };
+use rocket::response::content::RawHtml;
 
-struct UserData {
-    email: String,
-    status: AccountStatus,
-}
+mod page;
 
-impl UserData {
-    fn new(email: String) -> Self {
-        UserData {
-            email,
-            status: AccountStatus::Unconfirmed,
-        }
-    }
-}
+use page::Page;
 
-// Structure pour stocker les utilisateurs
-struct UserDatabase {
-    users: HashMap<String, UserData>,
-}
+#[macro_use]
+extern crate ini;
+
+// Chemin de la base partagée avec le serveur d'inscription
+struct DatabasePath(PathBuf);
 
-impl UserDatabase {
-    fn new() -> Self {
-        UserDatabase {
-            users: HashMap::new(),
+// Route pour confirmer un compte, depuis le lien de l'email d'inscription
+#[get("/confirm/<token>")]
+async fn confirm_account(
+    token: String,
+    cookies: &CookieJar<'_>,
+    database_path: &rocket::State<DatabasePath>
+) -> (Status, RawHtml<String>) {
+    // SQLite bloque, on ne doit pas occuper les threads de Rocket pendant
+    // l'attente du verrou
+    let (path, confirmed_token) = (database_path.0.clone(), token.clone());
+    let confirmed = rocket::tokio::task::spawn_blocking(move || {
+        let mut conn = database::open(&path).map_err(ConfirmError::Storage)?;
+        confirmation::confirm(&mut conn, &confirmed_token)
+    }).await;
+    let result = match confirmed {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("La confirmation a échoué : {}", e);
+            return Page::Unavailable.render();
         }
-    }
+    };
+    let page = match result {
+        Ok(account) => {
+            println!("Compte {} confirmé", account.login);
+            cookies.add(Cookie::build(("user_id", account.id.clone())).same_site(SameSite::Lax));
+            Page::Confirmed { login: account.login }
+        }
+        Err(ConfirmError::NotFound) => Page::NotFound,
+        Err(ConfirmError::Expired) => Page::Expired,
+        Err(
+            | ConfirmError::AlreadyUsed
+            | ConfirmError::Account(TransitionError::Forbidden { from: AccountStatus::Confirmed, .. }),
+        ) => Page::AlreadyConfirmed,
+        Err(ConfirmError::Account(TransitionError::Forbidden { .. } | TransitionError::NotFound)) => {
+            Page::Refused
+        }
+        Err(ConfirmError::Storage(e) | ConfirmError::Account(TransitionError::Storage(e))) => {
+            // Le jeton est encore valide, seul son début est journalisé
+            eprintln!("Impossible de confirmer le compte du jeton {}... : {}", token_prefix(&token), e);
+            Page::Unavailable
+        }
+    };
+    page.render()
+}
 
-    fn add_user(&mut self, uuid: String, email: String) {
-        self.users.insert(uuid, UserData::new(email));
-    }
+fn token_prefix(token: &str) -> &str {
+    token.get(..8).unwrap_or(token)
+}
 
-    fn confirm_account(&mut self, uuid: &str) -> Result<(), &'static str> {
-        if let Some(user) = self.users.get_mut(uuid) {
-            user.status = AccountStatus::Confirmed;
-            Ok(())
-        } else {
-            Err("Utilisateur non trouvé")
-        }
-    }
+// Toute autre adresse, par exemple un lien tronqué
+#[catch(404)]
+fn not_found() -> (Status, RawHtml<String>) {
+    Page::NotFound.render()
 }
 
-// Route pour confirmer un compte
-#[get("/confirm/<uuid>")]
-fn confirm_account(
-    uuid: String,
-    cookies: &CookieJar<'_>,
-    user_db: &rocket::State<UserDatabase>,
-) -> status::Custom<String> {
-    match user_db.confirm_account(&uuid) {
-        Ok(()) => {
-            cookies.add(Cookie::new("user_id", uuid.clone()).same_site());
-            status::Custom(
-                Status::Ok,
-                format!("Compte confirmé avec succès pour l'UUID : {}", uuid),
-            )
-        }
-        Err(_) => status::Custom(Status::NotFound, "UUID invalide".to_string()),
+/// `[database] path` du fichier de configuration du serveur d'inscription,
+/// remplacé par TERMPLAY_DATABASE_PATH s'il est défini
+fn database_path() -> anyhow::Result<PathBuf> {
+    if let Ok(path) = env::var("TERMPLAY_DATABASE_PATH") {
+        return Ok(PathBuf::from(path));
     }
+    let conf_file_path = env
+        ::args()
+        .nth(1)
+        .ok_or_else(|| anyhow::anyhow!("Usage: termplay-confirmation-server <config file path>"))?;
+    let conf = ini!(safe conf_file_path.as_str()).map_err(|e| anyhow::anyhow!(e))?;
+    conf.get("database")
+        .and_then(|section| section.get("path"))
+        .and_then(|path| path.clone())
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow::anyhow!("[database] path manquant dans {}", conf_file_path))
 }
 
 #[launch]
 fn rocket() -> _ {
-    let user_db = UserDatabase::new();
+    let database_path = match database_path() {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    // Le schéma appartient au serveur d'inscription, on le met quand même à
+    // jour pour pouvoir démarrer en premier
+    let migrated = database
+        ::open(&database_path)
+        .map_err(anyhow::Error::from)
+        .and_then(|mut conn| database::migrations::migrate(&mut conn));
+    if let Err(e) = migrated {
+        eprintln!("Impossible de préparer la base {} : {:#}", database_path.display(), e);
+        std::process::exit(1);
+    }
     rocket::build()
         .mount("/", routes![confirm_account])
-        .manage(user_db)
+        .register("/", catchers![not_found])
+        .manage(DatabasePath(database_path))
 }
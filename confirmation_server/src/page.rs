@@ -0,0 +1,72 @@
+use common::html::escape_html;
+use rocket::http::Status;
+use rocket::response::content::RawHtml;
+
+const LAYOUT: &str = include_str!("../templates/page.html");
+
+// Ce que la page de confirmation annonce à l'utilisateur
+pub enum Page {
+    Confirmed {
+        login: String,
+    },
+    AlreadyConfirmed,
+    NotFound,
+    // Lien resté inutilisé trop longtemps, l'inscription est à refaire
+    Expired,
+    // Compte supprimé ou suspendu
+    Refused,
+    Unavailable,
+}
+
+impl Page {
+    fn status(&self) -> Status {
+        match self {
+            Page::Confirmed { .. } | Page::AlreadyConfirmed => Status::Ok,
+            Page::NotFound => Status::NotFound,
+            Page::Expired => Status::Gone,
+            Page::Refused => Status::Conflict,
+            Page::Unavailable => Status::ServiceUnavailable,
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            Page::Confirmed { .. } => "Compte confirmé",
+            Page::AlreadyConfirmed => "Compte déjà confirmé",
+            Page::NotFound => "Lien invalide",
+            Page::Expired => "Lien expiré",
+            Page::Refused => "Confirmation impossible",
+            Page::Unavailable => "Service indisponible",
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Page::Confirmed { login } =>
+                format!(
+                    "Le compte {} est confirmé, vous pouvez vous connecter depuis termplay.",
+                    escape_html(login)
+                ),
+            Page::AlreadyConfirmed =>
+                "Ce compte est déjà confirmé, vous pouvez vous connecter depuis termplay.".to_string(),
+            Page::NotFound =>
+                "Ce lien de confirmation ne correspond à aucun compte. Vérifiez qu'il a été copié en entier.".to_string(),
+            Page::Expired =>
+                "Ce lien de confirmation a expiré. Inscrivez-vous de nouveau depuis termplay pour en recevoir un autre.".to_string(),
+            Page::Refused => "Ce compte ne peut plus être confirmé.".to_string(),
+            Page::Unavailable =>
+                "La confirmation n'a pas pu être enregistrée, réessayez dans quelques minutes.".to_string(),
+        }
+    }
+
+    pub fn render(&self) -> (Status, RawHtml<String>) {
+        let kind = match self.status().class().is_success() {
+            true => "success",
+            false => "failure",
+        };
+        let html = LAYOUT.replace("{{kind}}", kind)
+            .replace("{{title}}", self.title())
+            .replace("{{message}}", &self.message());
+        (self.status(), RawHtml(html))
+    }
+}
@@ -0,0 +1,8 @@
+-- Registrations waiting for their email to be confirmed. Databases created
+-- before migrations existed already have it.
+CREATE TABLE IF NOT EXISTS pre_register (
+    id TEXT PRIMARY KEY,
+    login TEXT,
+    password TEXT,
+    salt TEXT
+);
@@ -0,0 +1,18 @@
+-- Accounts replace pre-registrations, which all become unconfirmed accounts.
+-- The password is a bcrypt hash, the salt is part of it.
+CREATE TABLE accounts (
+    id TEXT PRIMARY KEY,
+    login TEXT NOT NULL,
+    password TEXT NOT NULL,
+    status TEXT NOT NULL CHECK (status IN ('unconfirmed', 'confirmed', 'suspended', 'deleted')),
+    created_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL
+);
+CREATE INDEX accounts_login ON accounts (login);
+
+INSERT INTO accounts (id, login, password, status, created_at, updated_at)
+    SELECT id, login, password, 'unconfirmed', CAST(strftime('%s', 'now') AS INTEGER), CAST(strftime('%s', 'now') AS INTEGER)
+    FROM pre_register
+    WHERE login IS NOT NULL AND password IS NOT NULL;
+
+DROP TABLE pre_register;
@@ -0,0 +1,13 @@
+-- Accounts get an email address, and both the login and the address must be
+-- unique whatever their case among accounts that are not deleted. NOCASE only
+-- folds ASCII letters.
+ALTER TABLE accounts ADD COLUMN email TEXT;
+
+-- Logins registered twice before this migration keep their latest account
+UPDATE accounts SET status = 'deleted'
+    WHERE status != 'deleted' AND rowid NOT IN (
+        SELECT MAX(rowid) FROM accounts WHERE status != 'deleted' GROUP BY login COLLATE NOCASE
+    );
+
+CREATE UNIQUE INDEX accounts_login_unique ON accounts (login COLLATE NOCASE) WHERE status != 'deleted';
+CREATE UNIQUE INDEX accounts_email_unique ON accounts (email COLLATE NOCASE) WHERE status != 'deleted';
@@ -0,0 +1,18 @@
+-- Confirmation links carry a random token instead of the account id. A token
+-- expires and can only be used once, an account still unconfirmed when all its
+-- tokens expired is deleted.
+CREATE TABLE confirmation_tokens (
+    token TEXT PRIMARY KEY,
+    account_id TEXT NOT NULL REFERENCES accounts (id),
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL,
+    used_at INTEGER
+);
+CREATE INDEX confirmation_tokens_account ON confirmation_tokens (account_id);
+CREATE INDEX confirmation_tokens_expiry ON confirmation_tokens (expires_at);
+
+-- Links already sent out held the account id, they keep working for a week
+INSERT INTO confirmation_tokens (token, account_id, created_at, expires_at)
+    SELECT id, id, CAST(strftime('%s', 'now') AS INTEGER), CAST(strftime('%s', 'now') AS INTEGER) + 7 * 24 * 60 * 60
+    FROM accounts
+    WHERE status = 'unconfirmed';
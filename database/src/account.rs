@@ -0,0 +1,327 @@
+use std::fmt;
+use std::str::FromStr;
+
+use rusqlite::types::{ FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef };
+use rusqlite::{ params, Connection, OptionalExtension, Row };
+use uuid::Uuid;
+
+use crate::unix_now;
+
+/// Where an account stands in its lifecycle.
+///
+/// An account starts unconfirmed until its email is confirmed. A confirmed
+/// account can be suspended and reinstated, and any account can be deleted,
+/// which is final.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountStatus {
+    Unconfirmed,
+    Confirmed,
+    Suspended,
+    Deleted,
+}
+
+impl AccountStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AccountStatus::Unconfirmed => "unconfirmed",
+            AccountStatus::Confirmed => "confirmed",
+            AccountStatus::Suspended => "suspended",
+            AccountStatus::Deleted => "deleted",
+        }
+    }
+
+    pub fn can_become(self, status: AccountStatus) -> bool {
+        use AccountStatus::*;
+        matches!(
+            (self, status),
+            (Unconfirmed, Confirmed) |
+                (Confirmed, Suspended) |
+                (Suspended, Confirmed) |
+                (Unconfirmed | Confirmed | Suspended, Deleted)
+        )
+    }
+}
+
+impl fmt::Display for AccountStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for AccountStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unconfirmed" => Ok(AccountStatus::Unconfirmed),
+            "confirmed" => Ok(AccountStatus::Confirmed),
+            "suspended" => Ok(AccountStatus::Suspended),
+            "deleted" => Ok(AccountStatus::Deleted),
+            _ => anyhow::bail!("Unknown account status '{}'", s),
+        }
+    }
+}
+
+impl ToSql for AccountStatus {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for AccountStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e: anyhow::Error| FromSqlError::Other(e.into()))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub id: String,
+    pub login: String,
+    /// Missing for accounts registered before emails were asked for
+    pub email: Option<String>,
+    /// bcrypt hash of the password
+    pub password: String,
+    pub status: AccountStatus,
+}
+
+impl Account {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get(0)?,
+            login: row.get(1)?,
+            email: row.get(2)?,
+            password: row.get(3)?,
+            status: row.get(4)?,
+        })
+    }
+}
+
+/// Why an account could not be created
+#[derive(Debug)]
+pub enum CreateError {
+    LoginTaken,
+    EmailTaken,
+    Storage(rusqlite::Error),
+}
+
+impl fmt::Display for CreateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CreateError::LoginTaken => write!(f, "The login is already taken"),
+            CreateError::EmailTaken => write!(f, "The email address is already registered"),
+            CreateError::Storage(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CreateError {}
+
+impl From<rusqlite::Error> for CreateError {
+    fn from(e: rusqlite::Error) -> Self {
+        CreateError::Storage(e)
+    }
+}
+
+fn is_taken(conn: &Connection, column: &str, value: &str) -> rusqlite::Result<bool> {
+    conn.query_row(
+        &format!(
+            "SELECT EXISTS (SELECT 1 FROM accounts WHERE {} = ?1 COLLATE NOCASE AND status != 'deleted')",
+            column
+        ),
+        params![value],
+        |row| row.get(0)
+    )
+}
+
+/// Which of `login` and `email` is already used. [create] checks again, this
+/// is for refusing a registration before doing any costly work.
+pub fn conflict(conn: &Connection, login: &str, email: &str) -> rusqlite::Result<Option<CreateError>> {
+    if is_taken(conn, "login", login)? {
+        return Ok(Some(CreateError::LoginTaken));
+    }
+    if is_taken(conn, "email", email)? {
+        return Ok(Some(CreateError::EmailTaken));
+    }
+    Ok(None)
+}
+
+/// Create an unconfirmed account, unless another one that is not deleted
+/// already has the same login or email, ignoring case
+pub fn create(
+    conn: &Connection,
+    login: &str,
+    email: &str,
+    password_hash: &str
+) -> Result<Account, CreateError> {
+    if let Some(conflict) = conflict(conn, login, email)? {
+        return Err(conflict);
+    }
+
+    let now = unix_now() as i64;
+    let account = Account {
+        id: Uuid::new_v4().to_string(),
+        login: login.to_string(),
+        email: Some(email.to_string()),
+        password: password_hash.to_string(),
+        status: AccountStatus::Unconfirmed,
+    };
+    let inserted = conn.execute(
+        "INSERT INTO accounts (id, login, email, password, status, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)",
+        params![account.id, account.login, account.email, account.password, account.status, now]
+    );
+    match inserted {
+        Ok(_) => Ok(account),
+        // Registered by someone else between the check and the insert
+        Err(e) if e.sqlite_error_code() == Some(rusqlite::ErrorCode::ConstraintViolation) => {
+            Err(conflict(conn, login, email)?.unwrap_or(CreateError::Storage(e)))
+        }
+        Err(e) => Err(CreateError::Storage(e)),
+    }
+}
+
+pub fn find(conn: &Connection, id: &str) -> rusqlite::Result<Option<Account>> {
+    conn.query_row(
+        "SELECT id, login, email, password, status FROM accounts WHERE id = ?1",
+        params![id],
+        Account::from_row
+    ).optional()
+}
+
+/// The account registered under `login` whatever its case, the latest one
+/// if older ones were deleted
+pub fn find_by_login(conn: &Connection, login: &str) -> rusqlite::Result<Option<Account>> {
+    conn.query_row(
+        "SELECT id, login, email, password, status FROM accounts WHERE login = ?1 COLLATE NOCASE ORDER BY status = 'deleted', created_at DESC, rowid DESC LIMIT 1",
+        params![login],
+        Account::from_row
+    ).optional()
+}
+
+/// Why an account could not change status
+#[derive(Debug)]
+pub enum TransitionError {
+    NotFound,
+    /// The account is not in a status it can leave for the requested one
+    Forbidden {
+        from: AccountStatus,
+        to: AccountStatus,
+    },
+    Storage(rusqlite::Error),
+}
+
+impl fmt::Display for TransitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransitionError::NotFound => write!(f, "Unknown account"),
+            TransitionError::Forbidden { from, to } => {
+                write!(f, "A {} account cannot become {}", from, to)
+            }
+            TransitionError::Storage(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for TransitionError {}
+
+impl From<rusqlite::Error> for TransitionError {
+    fn from(e: rusqlite::Error) -> Self {
+        TransitionError::Storage(e)
+    }
+}
+
+/// Move the account to `status` if its current one allows it
+pub fn set_status(
+    conn: &Connection,
+    id: &str,
+    status: AccountStatus
+) -> Result<Account, TransitionError> {
+    let account = find(conn, id)?.ok_or(TransitionError::NotFound)?;
+    if !account.status.can_become(status) {
+        return Err(TransitionError::Forbidden { from: account.status, to: status });
+    }
+    // Checking the status again makes a concurrent transition lose cleanly
+    let updated = conn.execute(
+        "UPDATE accounts SET status = ?1, updated_at = ?2 WHERE id = ?3 AND status = ?4",
+        params![status, unix_now() as i64, id, account.status]
+    )?;
+    if updated == 0 {
+        return Err(TransitionError::Forbidden { from: account.status, to: status });
+    }
+    Ok(Account { status, ..account })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations::migrate;
+    use AccountStatus::*;
+
+    const STATUSES: [AccountStatus; 4] = [Unconfirmed, Confirmed, Suspended, Deleted];
+
+    fn database() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        conn
+    }
+
+    #[test]
+    fn allowed_transitions() {
+        let allowed = [
+            (Unconfirmed, Confirmed),
+            (Unconfirmed, Deleted),
+            (Confirmed, Suspended),
+            (Confirmed, Deleted),
+            (Suspended, Confirmed),
+            (Suspended, Deleted),
+        ];
+        for from in STATUSES {
+            for to in STATUSES {
+                assert_eq!(from.can_become(to), allowed.contains(&(from, to)), "{} to {}", from, to);
+            }
+        }
+    }
+
+    #[test]
+    fn status_round_trips_through_text() {
+        for status in STATUSES {
+            assert_eq!(status.as_str().parse::<AccountStatus>().unwrap(), status);
+        }
+        assert!("banned".parse::<AccountStatus>().is_err());
+    }
+
+    #[test]
+    fn set_status_follows_the_lifecycle() {
+        let conn = database();
+        let account = create(&conn, "alice", "alice@example.com", "hash").unwrap();
+
+        assert_eq!(set_status(&conn, &account.id, Confirmed).unwrap().status, Confirmed);
+        assert!(
+            matches!(
+                set_status(&conn, &account.id, Confirmed),
+                Err(TransitionError::Forbidden { from: Confirmed, to: Confirmed })
+            )
+        );
+        set_status(&conn, &account.id, Deleted).unwrap();
+        assert!(
+            matches!(
+                set_status(&conn, &account.id, Confirmed),
+                Err(TransitionError::Forbidden { from: Deleted, .. })
+            )
+        );
+        assert_eq!(find(&conn, &account.id).unwrap().unwrap().status, Deleted);
+        assert!(matches!(set_status(&conn, "unknown", Confirmed), Err(TransitionError::NotFound)));
+    }
+
+    #[test]
+    fn login_and_email_are_unique_whatever_their_case() {
+        let conn = database();
+        create(&conn, "alice", "alice@example.com", "hash").unwrap();
+
+        assert!(matches!(create(&conn, "ALICE", "other@example.com", "hash"), Err(CreateError::LoginTaken)));
+        assert!(matches!(create(&conn, "bob", "Alice@Example.com", "hash"), Err(CreateError::EmailTaken)));
+    }
+}
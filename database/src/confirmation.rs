@@ -0,0 +1,268 @@
+use std::fmt;
+
+use rusqlite::{ params, Connection, OptionalExtension, TransactionBehavior };
+
+use crate::account::{ self, Account, AccountStatus, TransitionError };
+use crate::{ generate_token, unix_now };
+
+/// How long expired tokens are kept after their expiry, so an old link
+/// still tells the user it expired rather than that it never existed
+pub const EXPIRED_TOKEN_RETENTION_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// Secret of a confirmation link
+#[derive(Debug, Clone)]
+pub struct ConfirmationToken {
+    pub token: String,
+    pub expires_at: u64,
+}
+
+/// Issue a token confirming `account_id`, valid for `lifetime_secs`
+pub fn create(
+    conn: &Connection,
+    account_id: &str,
+    lifetime_secs: u64
+) -> rusqlite::Result<ConfirmationToken> {
+    let now = unix_now();
+    let token = ConfirmationToken {
+        token: generate_token(),
+        expires_at: now + lifetime_secs,
+    };
+    conn.execute(
+        "INSERT INTO confirmation_tokens (token, account_id, created_at, expires_at) VALUES (?1, ?2, ?3, ?4)",
+        params![token.token, account_id, now as i64, token.expires_at as i64]
+    )?;
+    Ok(token)
+}
+
+/// Why a token did not confirm its account
+#[derive(Debug)]
+pub enum ConfirmError {
+    /// Unknown token, or expired long enough ago to be purged
+    NotFound,
+    Expired,
+    /// The token confirmed its account already
+    AlreadyUsed,
+    /// The account cannot be confirmed, e.g. it was deleted
+    Account(TransitionError),
+    Storage(rusqlite::Error),
+}
+
+impl fmt::Display for ConfirmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfirmError::NotFound => write!(f, "Unknown confirmation token"),
+            ConfirmError::Expired => write!(f, "The confirmation token expired"),
+            ConfirmError::AlreadyUsed => write!(f, "The confirmation token was already used"),
+            ConfirmError::Account(e) => write!(f, "{}", e),
+            ConfirmError::Storage(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConfirmError {}
+
+impl From<rusqlite::Error> for ConfirmError {
+    fn from(e: rusqlite::Error) -> Self {
+        ConfirmError::Storage(e)
+    }
+}
+
+/// Use `token` to confirm its account. The token is spent only if the
+/// account is confirmed.
+pub fn confirm(conn: &mut Connection, token: &str) -> Result<Account, ConfirmError> {
+    // Locked before reading, a concurrent confirmation with the same token
+    // waits and then finds it used
+    let transaction = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let found: Option<(String, i64, Option<i64>)> = transaction
+        .query_row(
+            "SELECT account_id, expires_at, used_at FROM confirmation_tokens WHERE token = ?1",
+            params![token],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        )
+        .optional()?;
+    let (account_id, expires_at, used_at) = found.ok_or(ConfirmError::NotFound)?;
+    if used_at.is_some() {
+        return Err(ConfirmError::AlreadyUsed);
+    }
+    let now = unix_now() as i64;
+    if expires_at <= now {
+        return Err(ConfirmError::Expired);
+    }
+
+    // Checking used_at again makes a concurrent confirmation lose cleanly
+    let spent = transaction.execute(
+        "UPDATE confirmation_tokens SET used_at = ?1 WHERE token = ?2 AND used_at IS NULL",
+        params![now, token]
+    )?;
+    if spent == 0 {
+        return Err(ConfirmError::AlreadyUsed);
+    }
+    let account = account
+        ::set_status(&transaction, &account_id, AccountStatus::Confirmed)
+        .map_err(ConfirmError::Account)?;
+    transaction.commit()?;
+    Ok(account)
+}
+
+/// What [purge_expired] removed
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Purged {
+    /// Unconfirmed accounts whose every link expired, now deleted
+    pub accounts: usize,
+    pub tokens: usize,
+}
+
+/// Delete the unconfirmed accounts whose links all expired, freeing their
+/// login and email, then forget tokens that expired a while ago
+pub fn purge_expired(conn: &mut Connection) -> rusqlite::Result<Purged> {
+    let now = unix_now();
+    let transaction = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let accounts = transaction.execute(
+        "UPDATE accounts SET status = 'deleted', updated_at = ?1
+            WHERE status = 'unconfirmed'
+            AND EXISTS (SELECT 1 FROM confirmation_tokens WHERE account_id = accounts.id)
+            AND NOT EXISTS (SELECT 1 FROM confirmation_tokens WHERE account_id = accounts.id AND expires_at > ?1)",
+        params![now as i64]
+    )?;
+    let tokens = transaction.execute(
+        "DELETE FROM confirmation_tokens WHERE expires_at <= ?1",
+        params![now.saturating_sub(EXPIRED_TOKEN_RETENTION_SECS) as i64]
+    )?;
+    transaction.commit()?;
+    Ok(Purged { accounts, tokens })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{ Arc, Barrier };
+    use std::thread;
+
+    use super::*;
+    use crate::account::CreateError;
+    use crate::migrations::migrate;
+
+    const LIFETIME_SECS: u64 = 60 * 60;
+
+    fn database() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        conn
+    }
+
+    fn register(conn: &Connection, login: &str, lifetime_secs: u64) -> (Account, ConfirmationToken) {
+        let account = account::create(conn, login, &format!("{}@example.com", login), "hash").unwrap();
+        let token = create(conn, &account.id, lifetime_secs).unwrap();
+        (account, token)
+    }
+
+    #[test]
+    fn token_confirms_its_account_once() {
+        let mut conn = database();
+        let (account, token) = register(&conn, "alice", LIFETIME_SECS);
+
+        assert_eq!(confirm(&mut conn, &token.token).unwrap().id, account.id);
+        assert_eq!(account::find(&conn, &account.id).unwrap().unwrap().status, AccountStatus::Confirmed);
+        assert!(matches!(confirm(&mut conn, &token.token), Err(ConfirmError::AlreadyUsed)));
+        assert!(matches!(confirm(&mut conn, "unknown"), Err(ConfirmError::NotFound)));
+    }
+
+    #[test]
+    fn expired_token_is_refused() {
+        let mut conn = database();
+        let (account, token) = register(&conn, "alice", 0);
+
+        assert!(matches!(confirm(&mut conn, &token.token), Err(ConfirmError::Expired)));
+        assert_eq!(account::find(&conn, &account.id).unwrap().unwrap().status, AccountStatus::Unconfirmed);
+    }
+
+    #[test]
+    fn token_is_not_spent_when_the_account_cannot_be_confirmed() {
+        let mut conn = database();
+        let (account, token) = register(&conn, "alice", LIFETIME_SECS);
+        account::set_status(&conn, &account.id, AccountStatus::Deleted).unwrap();
+
+        assert!(
+            matches!(
+                confirm(&mut conn, &token.token),
+                Err(ConfirmError::Account(TransitionError::Forbidden { from: AccountStatus::Deleted, .. }))
+            )
+        );
+        let used_at: Option<i64> = conn
+            .query_row("SELECT used_at FROM confirmation_tokens WHERE token = ?1", params![token.token], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(used_at, None);
+    }
+
+    #[test]
+    fn concurrent_confirmations_spend_the_token_once() {
+        let path = std::env::temp_dir().join(format!("termplay-test-{}.sqlite3", generate_token()));
+        let mut conn = crate::open(&path).unwrap();
+        migrate(&mut conn).unwrap();
+        let (_, token) = register(&conn, "alice", LIFETIME_SECS);
+        drop(conn);
+
+        const CONFIRMATIONS: usize = 4;
+        let barrier = Arc::new(Barrier::new(CONFIRMATIONS));
+        let confirmations: Vec<_> = (0..CONFIRMATIONS)
+            .map(|_| {
+                let (path, token, barrier) = (path.clone(), token.token.clone(), barrier.clone());
+                thread::spawn(move || {
+                    let mut conn = crate::open(&path).unwrap();
+                    barrier.wait();
+                    confirm(&mut conn, &token)
+                })
+            })
+            .collect();
+        let results: Vec<_> = confirmations
+            .into_iter()
+            .map(|confirmation| confirmation.join().unwrap())
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        assert!(
+            results
+                .iter()
+                .filter(|result| result.is_err())
+                .all(|result| matches!(result, Err(ConfirmError::AlreadyUsed)))
+        );
+    }
+
+    #[test]
+    fn purge_frees_the_login_and_email_of_expired_registrations() {
+        let mut conn = database();
+        let (expired, expired_token) = register(&conn, "alice", 0);
+        let (pending, _) = register(&conn, "bob", LIFETIME_SECS);
+        assert!(
+            matches!(
+                account::create(&conn, "alice", "alice@example.com", "hash"),
+                Err(CreateError::LoginTaken)
+            )
+        );
+
+        let purged = purge_expired(&mut conn).unwrap();
+
+        assert_eq!(purged.accounts, 1);
+        assert_eq!(account::find(&conn, &expired.id).unwrap().unwrap().status, AccountStatus::Deleted);
+        assert_eq!(account::find(&conn, &pending.id).unwrap().unwrap().status, AccountStatus::Unconfirmed);
+        account::create(&conn, "alice", "alice@example.com", "hash").unwrap();
+        // Kept for a while so the old link says it expired
+        assert!(matches!(confirm(&mut conn, &expired_token.token), Err(ConfirmError::Expired)));
+    }
+
+    #[test]
+    fn purge_keeps_confirmed_accounts() {
+        let mut conn = database();
+        let (account, token) = register(&conn, "alice", LIFETIME_SECS);
+        confirm(&mut conn, &token.token).unwrap();
+        conn.execute("UPDATE confirmation_tokens SET expires_at = 0", []).unwrap();
+
+        let purged = purge_expired(&mut conn).unwrap();
+
+        assert_eq!(purged.accounts, 0);
+        assert_eq!(purged.tokens, 1);
+        assert_eq!(account::find(&conn, &account.id).unwrap().unwrap().status, AccountStatus::Confirmed);
+    }
+}
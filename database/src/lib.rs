@@ -0,0 +1,41 @@
+//! The SQLite database shared by the register and confirmation servers
+
+use std::path::Path;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+use rand::RngCore;
+use rusqlite::Connection;
+
+pub mod account;
+pub mod confirmation;
+pub mod migrations;
+pub mod outbox;
+
+const TOKEN_BYTES: usize = 32;
+
+/// Random hex token from the operating system's secure generator
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// How long a statement waits for another connection, possibly in the
+/// other server, to release its lock before failing
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn open(path: &Path) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    Ok(conn)
+}
@@ -0,0 +1,130 @@
+use anyhow::Context;
+use rusqlite::{ params, Connection, TransactionBehavior };
+
+use crate::unix_now;
+
+/// Schema changes in the order they were made. The version of a database is
+/// the number of migrations applied to it, so entries are only ever appended.
+const MIGRATIONS: &[(&str, &str)] = &[
+    ("pre_register", include_str!("../migrations/0001_pre_register.sql")),
+    ("sessions", include_str!("../migrations/0002_sessions.sql")),
+    ("accounts", include_str!("../migrations/0003_accounts.sql")),
+    ("account_emails", include_str!("../migrations/0004_account_emails.sql")),
+    ("outbox", include_str!("../migrations/0005_outbox.sql")),
+    ("confirmation_tokens", include_str!("../migrations/0006_confirmation_tokens.sql")),
+];
+
+fn current_version(conn: &Connection) -> rusqlite::Result<usize> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY, name TEXT NOT NULL, applied_at INTEGER NOT NULL)",
+        []
+    )?;
+    let version: i64 = conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+        [],
+        |row| row.get(0)
+    )?;
+    Ok(version as usize)
+}
+
+/// Bring the database up to the latest schema, each migration in its own
+/// transaction.
+///
+/// Both servers migrate at startup. Each transaction takes the write lock
+/// before reading the version, so when they start together one waits for
+/// the other and then finds the migration already applied.
+pub fn migrate(conn: &mut Connection) -> anyhow::Result<()> {
+    loop {
+        let transaction = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let version = current_version(&transaction).context("Could not read the schema version")?;
+        if version > MIGRATIONS.len() {
+            anyhow::bail!(
+                "The database schema is at version {} but this server only knows {}, it was upgraded by a newer server",
+                version,
+                MIGRATIONS.len()
+            );
+        }
+        let Some((name, sql)) = MIGRATIONS.get(version) else {
+            return Ok(());
+        };
+        let version = version + 1;
+        println!("Migrating the database to version {} ({})", version, name);
+        transaction
+            .execute_batch(sql)
+            .with_context(|| format!("Migration {} ({}) failed", version, name))?;
+        transaction.execute(
+            "INSERT INTO schema_version (version, name, applied_at) VALUES (?1, ?2, ?3)",
+            params![version as i64, name, unix_now() as i64]
+        )?;
+        transaction.commit()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tables(conn: &Connection) -> Vec<String> {
+        let mut statement = conn
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+            .unwrap();
+        let names = statement.query_map([], |row| row.get(0)).unwrap();
+        names.collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn migrating_twice_changes_nothing() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        let schema = tables(&conn);
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(tables(&conn), schema);
+        assert_eq!(current_version(&conn).unwrap(), MIGRATIONS.len());
+        let applied: i64 = conn
+            .query_row("SELECT COUNT(*) FROM schema_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(applied as usize, MIGRATIONS.len());
+    }
+
+    #[test]
+    fn upgrades_a_database_from_before_migrations() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE pre_register (id TEXT PRIMARY KEY, login TEXT, password TEXT, salt TEXT);
+            INSERT INTO pre_register VALUES ('id-1', 'alice', 'hash', NULL);
+            INSERT INTO pre_register VALUES ('id-2', NULL, NULL, NULL);"
+        ).unwrap();
+
+        migrate(&mut conn).unwrap();
+
+        assert!(!tables(&conn).contains(&"pre_register".to_string()));
+        let accounts: Vec<(String, String, String)> = conn
+            .prepare("SELECT id, login, status FROM accounts")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(accounts, [("id-1".to_string(), "alice".to_string(), "unconfirmed".to_string())]);
+        // The link already sent to alice still confirms the account
+        let token_account: String = conn
+            .query_row("SELECT account_id FROM confirmation_tokens WHERE token = 'id-1'", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(token_account, "id-1");
+    }
+
+    #[test]
+    fn refuses_a_database_from_a_newer_server() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        conn.execute(
+            "INSERT INTO schema_version (version, name, applied_at) VALUES (?1, 'future', 0)",
+            params![(MIGRATIONS.len() + 1) as i64]
+        ).unwrap();
+
+        assert!(migrate(&mut conn).is_err());
+    }
+}
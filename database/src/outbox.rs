@@ -0,0 +1,90 @@
+use rusqlite::{ params, Connection };
+
+use crate::unix_now;
+
+/// An email waiting in the outbox
+#[derive(Debug, Clone)]
+pub struct OutboxMessage {
+    pub id: i64,
+    pub recipient: String,
+    pub subject: String,
+    pub html: String,
+    pub text: String,
+    /// Failed deliveries so far
+    pub attempts: u32,
+}
+
+/// Queue an email for delivery as soon as possible
+pub fn enqueue(
+    conn: &Connection,
+    recipient: &str,
+    subject: &str,
+    html: &str,
+    text: &str
+) -> rusqlite::Result<i64> {
+    let now = unix_now() as i64;
+    conn.execute(
+        "INSERT INTO outbox (recipient, subject, html, text, next_attempt_at, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?5, ?5, ?5)",
+        params![recipient, subject, html, text, now]
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Pending messages whose next attempt is due, oldest first
+pub fn due(conn: &Connection, limit: usize) -> rusqlite::Result<Vec<OutboxMessage>> {
+    let mut statement = conn.prepare(
+        "SELECT id, recipient, subject, html, text, attempts FROM outbox WHERE status = 'pending' AND next_attempt_at <= ?1 ORDER BY next_attempt_at, id LIMIT ?2"
+    )?;
+    let messages = statement.query_map(params![unix_now() as i64, limit as i64], |row| {
+        Ok(OutboxMessage {
+            id: row.get(0)?,
+            recipient: row.get(1)?,
+            subject: row.get(2)?,
+            html: row.get(3)?,
+            text: row.get(4)?,
+            attempts: row.get(5)?,
+        })
+    })?;
+    messages.collect()
+}
+
+/// Unix time of the next pending attempt, if any
+pub fn next_attempt_at(conn: &Connection) -> rusqlite::Result<Option<u64>> {
+    let next: Option<i64> = conn.query_row(
+        "SELECT MIN(next_attempt_at) FROM outbox WHERE status = 'pending'",
+        [],
+        |row| row.get(0)
+    )?;
+    Ok(next.map(|at| at.max(0) as u64))
+}
+
+pub fn mark_sent(conn: &Connection, id: i64) -> rusqlite::Result<()> {
+    conn.execute(
+        "UPDATE outbox SET status = 'sent', attempts = attempts + 1, last_error = NULL, updated_at = ?1 WHERE id = ?2",
+        params![unix_now() as i64, id]
+    )?;
+    Ok(())
+}
+
+/// Record a failed attempt and try again at `next_attempt_at`
+pub fn retry_later(
+    conn: &Connection,
+    id: i64,
+    error: &str,
+    next_attempt_at: u64
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "UPDATE outbox SET attempts = attempts + 1, last_error = ?1, next_attempt_at = ?2, updated_at = ?3 WHERE id = ?4",
+        params![error, next_attempt_at as i64, unix_now() as i64, id]
+    )?;
+    Ok(())
+}
+
+/// Record the last failed attempt, the message will not be tried again
+pub fn mark_dead(conn: &Connection, id: i64, error: &str) -> rusqlite::Result<()> {
+    conn.execute(
+        "UPDATE outbox SET status = 'dead', attempts = attempts + 1, last_error = ?1, updated_at = ?2 WHERE id = ?3",
+        params![error, unix_now() as i64, id]
+    )?;
+    Ok(())
+}
@@ -0,0 +1,304 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use bcrypt::{ hash, verify, DEFAULT_COST };
+use common::command::{ ErrorCode, ErrorCommand, RegisterRejection, MAX_EMAIL_LENGTH };
+use database::account::{ self as accounts, Account, AccountStatus, CreateError };
+use database::confirmation;
+use rusqlite::Connection;
+
+use crate::session::{ self, Session, SessionRegistry };
+use crate::config::Config;
+use crate::outbox::{ self, OutboxNotifier };
+use crate::templates::TemplateContext;
+use crate::{ create_get_db_connection, report };
+
+/// Checked against when the login is unknown, so the reply takes as long as
+/// for a wrong password and does not tell which logins exist
+const DUMMY_HASH: &str = "$2b$12$S7/ncsOLcxRsrvZD1hWeXeIUtdkr69Oa2hr7HVNPiKkygKMo4pYGm";
+
+/// Why [register] did not create the account
+pub enum RegisterError {
+    /// Refused because of what the user typed
+    Rejected(RegisterRejection),
+    Failed(ErrorCommand),
+}
+
+impl From<ErrorCommand> for RegisterError {
+    fn from(e: ErrorCommand) -> Self {
+        RegisterError::Failed(e)
+    }
+}
+
+/// Loose syntax check, whether the address really exists is up to the
+/// confirmation email
+fn is_valid_email(email: &str) -> bool {
+    let (local, domain) = match email.rsplit_once('@') {
+        Some(parts) => parts,
+        None => {
+            return false;
+        }
+    };
+    email.len() <= MAX_EMAIL_LENGTH &&
+        !local.is_empty() &&
+        !email.chars().any(|c| c.is_whitespace() || c.is_control()) &&
+        domain.split('.').count() >= 2 &&
+        domain.split('.').all(|label| !label.is_empty() && !label.starts_with('-') && !label.ends_with('-'))
+}
+
+/// What to tell the user when their account could not be created
+fn rejection(e: &CreateError) -> Option<RegisterRejection> {
+    match e {
+        CreateError::LoginTaken => Some(RegisterRejection::LoginTaken),
+        CreateError::EmailTaken => Some(RegisterRejection::EmailTaken),
+        CreateError::Storage(_) => None,
+    }
+}
+
+/// Create the account and queue its confirmation email, both or neither
+pub fn register(
+    config: &Config,
+    notifier: &OutboxNotifier,
+    login: String,
+    email: String,
+    password: String,
+    locale: Option<String>
+) -> Result<(), RegisterError> {
+    let email = email.trim().to_string();
+    if login.trim().is_empty() {
+        return Err(RegisterError::Rejected(RegisterRejection::InvalidLogin));
+    }
+    if !is_valid_email(&email) {
+        return Err(RegisterError::Rejected(RegisterRejection::InvalidEmail));
+    }
+    if password.is_empty() {
+        return Err(RegisterError::Rejected(RegisterRejection::EmptyPassword));
+    }
+
+    let mut conn = create_get_db_connection(config).map_err(
+        report(ErrorCode::StorageUnavailable, "Could not open the database")
+    )?;
+
+    // Checked before hashing, which is slow on purpose
+    let conflict = accounts
+        ::conflict(&conn, &login, &email)
+        .map_err(report(ErrorCode::StorageUnavailable, "Could not read the accounts"))?;
+    if let Some(rejection) = conflict.as_ref().and_then(rejection) {
+        return Err(RegisterError::Rejected(rejection));
+    }
+
+    // The salt is generated by bcrypt and kept in the hash
+    let hashed_password = hash(password, DEFAULT_COST).map_err(
+        report(ErrorCode::Internal, "Could not hash the password")
+    )?;
+
+    let transaction = conn
+        .transaction()
+        .map_err(report(ErrorCode::StorageUnavailable, "Could not save the registration"))?;
+    // Checked again, another registration may have taken the login meanwhile
+    let created = accounts::create(&transaction, &login, &email, &hashed_password);
+    if let Some(rejection) = created.as_ref().err().and_then(rejection) {
+        return Err(RegisterError::Rejected(rejection));
+    }
+    let account = created.map_err(
+        report(ErrorCode::StorageUnavailable, "Could not save the registration")
+    )?;
+
+    let token = confirmation
+        ::create(&transaction, &account.id, config.confirmation.link_lifetime.as_secs())
+        .map_err(report(ErrorCode::StorageUnavailable, "Could not save the registration"))?;
+
+    let context = TemplateContext {
+        login: account.login,
+        confirm_url: format!("{}/confirm/{}", config.confirmation_url, token.token),
+        expires_at: token.expires_at,
+    };
+    let email = config.templates
+        .render(locale.as_deref(), email, &context)
+        .map_err(report(ErrorCode::Internal, "Could not write the confirmation email"))?;
+    outbox::enqueue(&transaction, &email)
+        .and_then(|_| transaction.commit())
+        .map_err(report(ErrorCode::StorageUnavailable, "Could not queue the confirmation email"))?;
+    notifier.notify();
+
+    Ok(())
+}
+
+/// Every `interval`, delete the registrations that were not confirmed before
+/// their link expired
+pub async fn purge_expired_registrations(config: Arc<Config>, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        let config = config.clone();
+        let purged = tokio::task::spawn_blocking(move || {
+            let mut conn = create_get_db_connection(&config)?;
+            Ok(confirmation::purge_expired(&mut conn)?)
+        }).await;
+        match purged.map_err(anyhow::Error::from).and_then(|purged| purged) {
+            Ok(purged) if purged.accounts > 0 => {
+                println!("Deleted {} registrations whose confirmation link expired", purged.accounts);
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Could not purge the expired registrations: {:#}", e),
+        }
+    }
+}
+
+/// Refuse accounts that may not log in, telling the user why
+fn check_status(account: &Account) -> Result<(), ErrorCommand> {
+    match account.status {
+        AccountStatus::Confirmed => Ok(()),
+        AccountStatus::Unconfirmed =>
+            Err(
+                ErrorCommand::new(
+                    ErrorCode::AccountNotConfirmed,
+                    "Please confirm your email address before logging in"
+                )
+            ),
+        AccountStatus::Suspended =>
+            Err(ErrorCommand::new(ErrorCode::AccountSuspended, "This account is suspended")),
+        AccountStatus::Deleted =>
+            Err(ErrorCommand::new(ErrorCode::AccountDeleted, "This account was deleted")),
+    }
+}
+
+/// Check `password` against the stored hash and open a session for `login`
+pub fn login(
+    config: &Config,
+    sessions: &SessionRegistry,
+    login: String,
+    password: String
+) -> Result<Session, ErrorCommand> {
+    let conn = create_get_db_connection(config).map_err(
+        report(ErrorCode::StorageUnavailable, "Could not open the database")
+    )?;
+
+    let account = accounts
+        ::find_by_login(&conn, &login)
+        .map_err(report(ErrorCode::StorageUnavailable, "Could not read the account"))?;
+
+    let invalid_credentials = || {
+        ErrorCommand::new(ErrorCode::InvalidCredentials, "Invalid login or password")
+    };
+    let Some(account) = account else {
+        let _ = verify(password, DUMMY_HASH);
+        return Err(invalid_credentials());
+    };
+    let matches = verify(password, &account.password).map_err(
+        report(ErrorCode::Internal, "Could not verify the password")
+    )?;
+    if !matches {
+        return Err(invalid_credentials());
+    }
+    // Only told once the password is known to be right
+    check_status(&account)?;
+
+    open_session(config, &conn, sessions, &account.login)
+}
+
+/// Open a session for the account named by the client certificate
+pub fn certificate_login(
+    config: &Config,
+    sessions: &SessionRegistry,
+    certificate_login: Option<String>
+) -> Result<Session, ErrorCommand> {
+    let login = certificate_login.ok_or_else(|| {
+        ErrorCommand::new(ErrorCode::InvalidCredentials, "No client certificate was presented")
+    })?;
+
+    let conn = create_get_db_connection(config).map_err(
+        report(ErrorCode::StorageUnavailable, "Could not open the database")
+    )?;
+    let account = accounts
+        ::find_by_login(&conn, &login)
+        .map_err(report(ErrorCode::StorageUnavailable, "Could not read the account"))?;
+    let account = match account {
+        Some(account) => account,
+        None => {
+            eprintln!("Client certificate for unknown account {}", login);
+            return Err(
+                ErrorCommand::new(
+                    ErrorCode::InvalidCredentials,
+                    "The client certificate does not belong to any account"
+                )
+            );
+        }
+    };
+    check_status(&account)?;
+
+    open_session(config, &conn, sessions, &login)
+}
+
+fn open_session(
+    config: &Config,
+    conn: &Connection,
+    sessions: &SessionRegistry,
+    login: &str
+) -> Result<Session, ErrorCommand> {
+    let session = session::create(conn, login, config.session_lifetime.as_secs()).map_err(
+        report(ErrorCode::StorageUnavailable, "Could not save the session")
+    )?;
+    sessions.insert(session.clone());
+    Ok(session)
+}
+
+pub fn resume(
+    config: &Config,
+    sessions: &SessionRegistry,
+    session_token: &str
+) -> Result<Session, ErrorCommand> {
+    let conn = create_get_db_connection(config).map_err(
+        report(ErrorCode::StorageUnavailable, "Could not open the database")
+    )?;
+    let session = sessions
+        .resume(&conn, session_token)
+        .map_err(report(ErrorCode::StorageUnavailable, "Could not read the session"))?
+        .ok_or_else(|| {
+            ErrorCommand::new(ErrorCode::InvalidSession, "Session expired, please log in again")
+        })?;
+
+    // The account may have been suspended or deleted since the login
+    let account = accounts
+        ::find_by_login(&conn, &session.login)
+        .map_err(report(ErrorCode::StorageUnavailable, "Could not read the account"))?;
+    let allowed = match &account {
+        Some(account) => check_status(account),
+        None => Err(ErrorCommand::new(ErrorCode::AccountDeleted, "This account was deleted")),
+    };
+    if let Err(e) = allowed {
+        println!("Closing the session of {}: {}", session.login, e.message);
+        sessions.remove(&session.token);
+        session::delete(&conn, &session.token).map_err(
+            report(ErrorCode::StorageUnavailable, "Could not delete the session")
+        )?;
+        return Err(e);
+    }
+    Ok(session)
+}
+
+/// End the session for good, it cannot be resumed afterwards
+pub fn logout(
+    config: &Config,
+    sessions: &SessionRegistry,
+    session: &Session
+) -> Result<(), ErrorCommand> {
+    let conn = create_get_db_connection(config).map_err(
+        report(ErrorCode::StorageUnavailable, "Could not open the database")
+    )?;
+    sessions.remove(&session.token);
+    session::delete(&conn, &session.token).map_err(
+        report(ErrorCode::StorageUnavailable, "Could not delete the session")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dummy_hash_costs_as_much_as_a_real_one() {
+        assert!(DUMMY_HASH.starts_with(&format!("$2b${}$", DEFAULT_COST)));
+        assert!(!verify("password", DUMMY_HASH).unwrap());
+    }
+}
@@ -0,0 +1,422 @@
+use common::command::{
+    ErrorCode,
+    ErrorCommand,
+    JoinGameCommand,
+    LoginCommand,
+    LoginResponseCommand,
+    Profile,
+    RegisterCommand,
+    RegisterResponseCommand,
+    ResumeCommand,
+    ResumeResponseCommand,
+    ServerCommand,
+    UserCommand,
+};
+use common::envelope::Envelope;
+use common::server::CommandManager;
+use std::sync::Arc;
+
+use tokio::io::{ AsyncRead, AsyncWrite };
+
+use crate::account::{ self, RegisterError };
+use crate::session::{ Session, SessionRegistry };
+use crate::config::{ Config, MalformedFramePolicy };
+use crate::outbox::OutboxNotifier;
+use crate::{ receive_command, run_blocking };
+
+/// Where a connection stands in the conversation, which decides the commands
+/// it may send
+#[derive(Debug)]
+enum ClientState {
+    Unauthenticated,
+    Authenticated(Session),
+    InLobby(Session),
+    /// The game is in the session's `game_id`. Games are entered from the
+    /// lobby and left back to it, there is no logging out in the middle.
+    InGame(Session),
+}
+
+impl ClientState {
+    fn name(&self) -> &'static str {
+        match self {
+            ClientState::Unauthenticated => "not logged in",
+            ClientState::Authenticated(_) => "logged in",
+            ClientState::InLobby(_) => "in the lobby",
+            ClientState::InGame(_) => "in a game",
+        }
+    }
+}
+
+/// One client connection after the handshake, serving its commands until
+/// it leaves
+pub struct ClientSession<S> {
+    config: Arc<Config>,
+    sessions: SessionRegistry,
+    outbox: OutboxNotifier,
+    cmd_manager: CommandManager<S>,
+    policy: MalformedFramePolicy,
+    /// Login named by the verified client certificate, if any
+    certificate_login: Option<String>,
+    state: ClientState,
+}
+
+impl<S> ClientSession<S> where S: AsyncRead + AsyncWrite + Unpin {
+    pub fn new(
+        config: Arc<Config>,
+        sessions: SessionRegistry,
+        outbox: OutboxNotifier,
+        cmd_manager: CommandManager<S>,
+        policy: MalformedFramePolicy,
+        certificate_login: Option<String>
+    ) -> Self {
+        Self {
+            config,
+            sessions,
+            outbox,
+            cmd_manager,
+            policy,
+            certificate_login,
+            state: ClientState::Unauthenticated,
+        }
+    }
+
+    /// Answer commands until the client closes the connection
+    pub async fn run(mut self) -> anyhow::Result<()> {
+        loop {
+            let request = match receive_command(&mut self.cmd_manager, self.policy).await? {
+                Some(request) => request,
+                None => {
+                    println!("Client disconnected");
+                    return Ok(());
+                }
+            };
+            let reply = match self.dispatch(request.command).await {
+                Some(reply) => reply,
+                None => {
+                    continue;
+                }
+            };
+            self.cmd_manager.send(&Envelope::reply(request.id, reply)).await?;
+        }
+    }
+
+    /// Run `command` if the current state allows it, returning the reply
+    async fn dispatch(&mut self, command: UserCommand) -> Option<ServerCommand> {
+        let result = match (&self.state, command) {
+            (_, UserCommand::Ping(_) | UserCommand::Pong(_)) => {
+                // Answered by the connection itself, never returned by receive
+                return None;
+            }
+            (_, UserCommand::Hello(_)) => {
+                println!("Unexpected hello received");
+                Err(
+                    ErrorCommand::new(
+                        ErrorCode::UnexpectedCommand,
+                        "The handshake has already been done"
+                    )
+                )
+            }
+            (_, UserCommand::Unknown) => {
+                println!("Unknown command received");
+                Err(
+                    ErrorCommand::new(
+                        ErrorCode::UnknownCommand,
+                        "This server does not know this command"
+                    )
+                )
+            }
+            (ClientState::Unauthenticated, UserCommand::Register(RegisterCommand { login, email, password, locale })) => {
+                self.register(login, email, password, locale).await
+            }
+            (ClientState::Unauthenticated, UserCommand::Login(LoginCommand { login, password })) => {
+                self.login(login, password).await
+            }
+            (ClientState::Unauthenticated, UserCommand::CertificateLogin) => {
+                self.certificate_login().await
+            }
+            (ClientState::Unauthenticated, UserCommand::Resume(ResumeCommand { session_token })) => {
+                self.resume(session_token).await
+            }
+            (ClientState::Authenticated(session), UserCommand::JoinLobby) => {
+                let session = session.clone();
+                self.join_lobby(session)
+            }
+            (ClientState::InLobby(session), UserCommand::LeaveLobby) => {
+                let session = session.clone();
+                self.leave_lobby(session)
+            }
+            (ClientState::InLobby(session), UserCommand::JoinGame(JoinGameCommand { game_id })) => {
+                let session = session.clone();
+                self.join_game(session, game_id)
+            }
+            (ClientState::InGame(session), UserCommand::LeaveGame) => {
+                let session = session.clone();
+                self.leave_game(session)
+            }
+            (ClientState::Authenticated(session) | ClientState::InLobby(session), UserCommand::Logout) => {
+                let session = session.clone();
+                self.logout(session).await
+            }
+            (state, _) => {
+                println!("Command refused while {}", state.name());
+                Err(
+                    ErrorCommand::new(
+                        ErrorCode::UnexpectedCommand,
+                        format!("This command is not allowed while {}", state.name())
+                    )
+                )
+            }
+        };
+        Some(result.unwrap_or_else(ServerCommand::Error))
+    }
+
+    async fn register(
+        &mut self,
+        login: String,
+        email: String,
+        password: String,
+        locale: Option<String>
+    ) -> Result<ServerCommand, ErrorCommand> {
+        println!("Registering user {}", login);
+        let (config, outbox) = (self.config.clone(), self.outbox.clone());
+        let registered = run_blocking(move || {
+            account::register(&config, &outbox, login, email, password, locale)
+        }).await;
+        let rejection = match registered {
+            Ok(()) => {
+                println!("Confirmation email queued");
+                None
+            }
+            Err(RegisterError::Rejected(rejection)) => {
+                println!("Registration refused: {}", rejection.message());
+                Some(rejection)
+            }
+            Err(RegisterError::Failed(e)) => {
+                return Err(e);
+            }
+        };
+        Ok(
+            ServerCommand::RegisterResponse(RegisterResponseCommand {
+                email_queued: rejection.is_none(),
+                rejection,
+            })
+        )
+    }
+
+    async fn login(&mut self, login: String, password: String) -> Result<ServerCommand, ErrorCommand> {
+        println!("Logging in user {}", login);
+        let (config, sessions) = (self.config.clone(), self.sessions.clone());
+        let session = run_blocking(move || account::login(&config, &sessions, login, password)).await?;
+        println!("Session opened for {}", session.login);
+        Ok(self.logged_in(session))
+    }
+
+    async fn certificate_login(&mut self) -> Result<ServerCommand, ErrorCommand> {
+        let (config, sessions) = (self.config.clone(), self.sessions.clone());
+        let certificate_login = self.certificate_login.clone();
+        let session = run_blocking(move || {
+            account::certificate_login(&config, &sessions, certificate_login)
+        }).await?;
+        println!("Session opened for {} with a client certificate", session.login);
+        Ok(self.logged_in(session))
+    }
+
+    fn logged_in(&mut self, session: Session) -> ServerCommand {
+        let reply = ServerCommand::LoginResponse(LoginResponseCommand {
+            session_token: session.token.clone(),
+            expires_at: session.expires_at,
+            profile: Profile {
+                login: session.login.clone(),
+            },
+        });
+        self.state = ClientState::Authenticated(session);
+        reply
+    }
+
+    async fn resume(&mut self, session_token: String) -> Result<ServerCommand, ErrorCommand> {
+        let (config, sessions) = (self.config.clone(), self.sessions.clone());
+        let session = run_blocking(move || account::resume(&config, &sessions, &session_token)).await?;
+        println!("Session resumed for {}", session.login);
+        let reply = ServerCommand::ResumeResponse(ResumeResponseCommand {
+            expires_at: session.expires_at,
+            profile: Profile {
+                login: session.login.clone(),
+            },
+            in_lobby: session.in_lobby,
+            game_id: session.game_id.clone(),
+        });
+        self.state = match (&session.game_id, session.in_lobby) {
+            (Some(_), _) => ClientState::InGame(session),
+            (None, true) => ClientState::InLobby(session),
+            (None, false) => ClientState::Authenticated(session),
+        };
+        Ok(reply)
+    }
+
+    fn join_lobby(&mut self, session: Session) -> Result<ServerCommand, ErrorCommand> {
+        println!("{} joined the lobby", session.login);
+        self.sessions.set_in_lobby(&session.token, true);
+        self.state = ClientState::InLobby(session);
+        Ok(ServerCommand::JoinLobbyResponse)
+    }
+
+    fn leave_lobby(&mut self, session: Session) -> Result<ServerCommand, ErrorCommand> {
+        println!("{} left the lobby", session.login);
+        self.sessions.set_in_lobby(&session.token, false);
+        self.state = ClientState::Authenticated(session);
+        Ok(ServerCommand::LeaveLobbyResponse)
+    }
+
+    /// Games are not implemented yet, any game id is taken as is
+    fn join_game(&mut self, mut session: Session, game_id: String) -> Result<ServerCommand, ErrorCommand> {
+        println!("{} joined game {}", session.login, game_id);
+        self.sessions.set_game(&session.token, Some(game_id.clone()));
+        session.game_id = Some(game_id);
+        self.state = ClientState::InGame(session);
+        Ok(ServerCommand::JoinGameResponse)
+    }
+
+    fn leave_game(&mut self, mut session: Session) -> Result<ServerCommand, ErrorCommand> {
+        println!("{} left their game", session.login);
+        self.sessions.set_game(&session.token, None);
+        session.game_id = None;
+        self.state = ClientState::InLobby(session);
+        Ok(ServerCommand::LeaveGameResponse)
+    }
+
+    async fn logout(&mut self, session: Session) -> Result<ServerCommand, ErrorCommand> {
+        let (config, sessions) = (self.config.clone(), self.sessions.clone());
+        let closed = session.clone();
+        run_blocking(move || account::logout(&config, &sessions, &closed)).await?;
+        println!("Session closed for {}", session.login);
+        self.state = ClientState::Unauthenticated;
+        Ok(ServerCommand::LogoutResponse)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use common::client;
+    use database::account::{ self as accounts, AccountStatus };
+    use tokio::io::{ duplex, DuplexStream };
+
+    use super::*;
+    use crate::config::ConfigSource;
+    use crate::{ create_get_db_connection, session };
+
+    fn config(dir: &Path) -> Arc<Config> {
+        let path = dir.join("termplay.ini");
+        fs::write(&path, format!("[database]\npath = {}\n", dir.join("termplay.sqlite3").display())).unwrap();
+        let config = Config::load(&ConfigSource::load(&path, &[]).unwrap(), false).unwrap();
+        let mut conn = create_get_db_connection(&config).unwrap();
+        database::migrations::migrate(&mut conn).unwrap();
+        Arc::new(config)
+    }
+
+    /// Token of a session opened for a confirmed account, as a login would
+    fn logged_in(config: &Config) -> String {
+        let conn = create_get_db_connection(config).unwrap();
+        let account = accounts::create(&conn, "alice", "alice@example.com", "hash").unwrap();
+        accounts::set_status(&conn, &account.id, AccountStatus::Confirmed).unwrap();
+        session::create(&conn, "alice", 60).unwrap().token
+    }
+
+    struct Client {
+        cmd_manager: client::CommandManager<DuplexStream>,
+        next_id: u64,
+    }
+
+    impl Client {
+        /// A connection served by its own [ClientSession]
+        fn connect(config: &Arc<Config>, sessions: &SessionRegistry) -> Self {
+            let (client, server) = duplex(4096);
+            let session = ClientSession::new(
+                config.clone(),
+                sessions.clone(),
+                OutboxNotifier::new(),
+                CommandManager::new(server),
+                MalformedFramePolicy::Reply,
+                None
+            );
+            tokio::spawn(session.run());
+            Self {
+                cmd_manager: client::CommandManager::new(client),
+                next_id: 1,
+            }
+        }
+
+        async fn request(&mut self, command: UserCommand) -> ServerCommand {
+            let id = self.next_id;
+            self.next_id += 1;
+            self.cmd_manager.send(&Envelope::request(id, command)).await.unwrap();
+            let reply = self.cmd_manager.receive().await.unwrap();
+            assert_eq!(reply.id, Some(id));
+            reply.command
+        }
+
+        async fn assert_refused(&mut self, command: UserCommand) {
+            match self.request(command.clone()).await {
+                ServerCommand::Error(ErrorCommand { code: ErrorCode::UnexpectedCommand, .. }) => {}
+                reply => panic!("{:?} was answered with {:?}", command, reply),
+            }
+        }
+    }
+
+    fn join_game() -> UserCommand {
+        UserCommand::JoinGame(JoinGameCommand { game_id: "game-1".to_string() })
+    }
+
+    fn resume(session_token: &str) -> UserCommand {
+        UserCommand::Resume(ResumeCommand { session_token: session_token.to_string() })
+    }
+
+    #[tokio::test]
+    async fn games_are_entered_from_the_lobby_and_left_back_to_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let token = logged_in(&config);
+        let mut client = Client::connect(&config, &SessionRegistry::new());
+
+        client.assert_refused(join_game()).await;
+        assert!(matches!(client.request(resume(&token)).await, ServerCommand::ResumeResponse(_)));
+        client.assert_refused(join_game()).await;
+        assert_eq!(client.request(UserCommand::JoinLobby).await, ServerCommand::JoinLobbyResponse);
+        assert_eq!(client.request(join_game()).await, ServerCommand::JoinGameResponse);
+
+        client.assert_refused(UserCommand::JoinLobby).await;
+        client.assert_refused(UserCommand::LeaveLobby).await;
+        client.assert_refused(UserCommand::Logout).await;
+        client.assert_refused(join_game()).await;
+
+        assert_eq!(client.request(UserCommand::LeaveGame).await, ServerCommand::LeaveGameResponse);
+        client.assert_refused(UserCommand::LeaveGame).await;
+        assert_eq!(client.request(UserCommand::Logout).await, ServerCommand::LogoutResponse);
+    }
+
+    #[tokio::test]
+    async fn resuming_goes_back_to_the_game() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let token = logged_in(&config);
+        let sessions = SessionRegistry::new();
+        let mut client = Client::connect(&config, &sessions);
+        client.request(resume(&token)).await;
+        client.request(UserCommand::JoinLobby).await;
+        client.request(join_game()).await;
+        drop(client);
+
+        let mut client = Client::connect(&config, &sessions);
+        match client.request(resume(&token)).await {
+            ServerCommand::ResumeResponse(ResumeResponseCommand { in_lobby, game_id, .. }) => {
+                assert!(in_lobby);
+                assert_eq!(game_id.as_deref(), Some("game-1"));
+            }
+            reply => panic!("Resume was answered with {:?}", reply),
+        }
+        client.assert_refused(UserCommand::Logout).await;
+        assert_eq!(client.request(UserCommand::LeaveGame).await, ServerCommand::LeaveGameResponse);
+    }
+}
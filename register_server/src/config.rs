@@ -0,0 +1,619 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Context;
+use common::connection::{
+    ConnectionConfig,
+    DEFAULT_IDLE_TIMEOUT,
+    DEFAULT_KEEPALIVE_INTERVAL,
+    DEFAULT_MAX_FRAME_LENGTH,
+};
+
+use crate::mailer::{ MailBackend, Sender, SmtpSecurity };
+use crate::session::DEFAULT_SESSION_LIFETIME_SECS;
+use crate::templates::Templates;
+use crate::tls::{ self, TlsSettings };
+
+/// Prefix of the environment variables overriding settings, see
+/// [env_var_name]
+const ENV_PREFIX: &str = "TERMPLAY_";
+
+/// Every setting the server reads, by section. Anything else in the
+/// configuration file is most likely a typo.
+const SETTINGS: &[(&str, &[&str])] = &[
+    (
+        "mail",
+        &[
+            "backend",
+            "sender",
+            "sender name",
+            "maildir path",
+            "templates path",
+            "default locale",
+            "confirmation url",
+        ],
+    ),
+    ("mailgun", &["domain", "api key", "region"]),
+    ("smtp", &["host", "port", "security", "username", "password"]),
+    (
+        "ssl",
+        &[
+            "format",
+            "cert file path",
+            "key file path",
+            "pkcs12 password",
+            "client certificates",
+            "client ca file path",
+            "reload interval",
+        ],
+    ),
+    ("database", &["path"]),
+    (
+        "connection",
+        &[
+            "max frame length",
+            "malformed frame policy",
+            "allow message pack",
+            "allow plaintext",
+            "max connections",
+            "handshake timeout",
+            "keepalive interval",
+            "idle timeout",
+        ],
+    ),
+    ("sessions", &["lifetime"]),
+    ("outbox", &["max attempts", "retry delay", "max retry delay", "send timeout"]),
+    ("confirmation", &["link lifetime", "purge interval"]),
+];
+
+fn is_known(section: &str, key: &str) -> bool {
+    SETTINGS.iter().any(|(known_section, keys)| *known_section == section && keys.contains(&key))
+}
+
+/// `[ssl] cert file path` is overridden by `TERMPLAY_SSL_CERT_FILE_PATH`
+fn env_var_name(section: &str, key: &str) -> String {
+    format!("{}{}_{}", ENV_PREFIX, section, key).replace(' ', "_").to_uppercase()
+}
+
+/// A `section.key=value` setting given on the command line
+#[derive(Debug, Clone)]
+pub struct Override {
+    section: String,
+    key: String,
+    value: String,
+}
+
+impl FromStr for Override {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s
+            .split_once('=')
+            .with_context(|| format!("Invalid setting '{}', expected section.key=value", s))?;
+        let (section, key) = name
+            .split_once('.')
+            .with_context(|| format!("Invalid setting '{}', expected section.key=value", s))?;
+        let (section, key) = (section.trim().to_lowercase(), key.trim().to_lowercase());
+        if !is_known(&section, &key) {
+            anyhow::bail!("Unknown setting [{}] {}", section, key);
+        }
+        Ok(Self { section, key, value: value.trim().to_string() })
+    }
+}
+
+/// Where a setting was read from, for error messages
+#[derive(Debug, Clone)]
+enum Origin {
+    File,
+    Env(String),
+    CommandLine,
+}
+
+/// Raw settings from the configuration file, the environment and the
+/// command line, later ones winning
+pub struct ConfigSource {
+    file_path: PathBuf,
+    values: HashMap<(String, String), (String, Origin)>,
+}
+
+impl ConfigSource {
+    pub fn load(file_path: &Path, overrides: &[Override]) -> anyhow::Result<Self> {
+        let path = file_path
+            .to_str()
+            .with_context(|| format!("Invalid configuration file path '{}'", file_path.display()))?;
+        let conf = ini!(safe path).map_err(|e| {
+            anyhow::anyhow!("Could not read configuration file '{}': {}", file_path.display(), e)
+        })?;
+
+        let mut values = HashMap::new();
+        for (section, keys) in conf {
+            for (key, value) in keys {
+                if !is_known(&section, &key) {
+                    eprintln!(
+                        "Warning: ignoring unknown setting [{}] {} in '{}'",
+                        section,
+                        key,
+                        file_path.display()
+                    );
+                    continue;
+                }
+                if let Some(value) = value {
+                    values.insert((section.clone(), key), (value, Origin::File));
+                }
+            }
+        }
+        for (section, keys) in SETTINGS {
+            for key in keys.iter() {
+                let name = env_var_name(section, key);
+                if let Ok(value) = env::var(&name) {
+                    values.insert((section.to_string(), key.to_string()), (value, Origin::Env(name)));
+                }
+            }
+        }
+        for Override { section, key, value } in overrides.iter().cloned() {
+            values.insert((section, key), (value, Origin::CommandLine));
+        }
+
+        Ok(Self {
+            file_path: file_path.to_path_buf(),
+            values,
+        })
+    }
+
+    /// The value of a setting, empty ones counting as unset
+    pub fn value(&self, section: &str, key: &str) -> Option<&str> {
+        self.values
+            .get(&(section.to_string(), key.to_string()))
+            .map(|(value, _)| value.as_str())
+            .filter(|value| !value.is_empty())
+    }
+
+    pub fn required(&self, section: &str, key: &str) -> anyhow::Result<&str> {
+        self.value(section, key).with_context(|| {
+            format!(
+                "Missing [{}] {} in '{}' (or {})",
+                section,
+                key,
+                self.file_path.display(),
+                env_var_name(section, key)
+            )
+        })
+    }
+
+    /// Error for a value that does not fit, saying where it came from
+    pub fn invalid(&self, section: &str, key: &str, expected: &str) -> anyhow::Error {
+        let (value, origin) = match self.values.get(&(section.to_string(), key.to_string())) {
+            Some((value, origin)) => (value.as_str(), origin),
+            None => ("", &Origin::File),
+        };
+        let origin = match origin {
+            Origin::File => format!("in '{}'", self.file_path.display()),
+            Origin::Env(name) => format!("from {}", name),
+            Origin::CommandLine => String::from("from --set"),
+        };
+        anyhow::anyhow!("Invalid [{}] {} '{}' {}, expected {}", section, key, value, origin, expected)
+    }
+
+    fn parse<T: FromStr>(&self, section: &str, key: &str, expected: &str) -> anyhow::Result<Option<T>> {
+        self.value(section, key)
+            .map(|value| value.parse().map_err(|_| self.invalid(section, key, expected)))
+            .transpose()
+    }
+
+    fn flag(&self, section: &str, key: &str, default: bool) -> anyhow::Result<bool> {
+        match self.value(section, key) {
+            Some("true") => Ok(true),
+            Some("false") => Ok(false),
+            Some(_) => Err(self.invalid(section, key, "'true' or 'false'")),
+            None => Ok(default),
+        }
+    }
+
+    /// Read a duration in seconds, `0` meaning disabled
+    fn seconds(
+        &self,
+        section: &str,
+        key: &str,
+        default: Option<Duration>
+    ) -> anyhow::Result<Option<Duration>> {
+        match self.parse(section, key, "a number of seconds")? {
+            Some(seconds) => Ok(Some(Duration::from_secs(seconds)).filter(|duration| !duration.is_zero())),
+            None => Ok(default),
+        }
+    }
+}
+
+/// What to do when a client sends a frame that is too large or not a command
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MalformedFramePolicy {
+    /// Close the connection straight away
+    Drop,
+    /// Answer with an [ErrorCode::InvalidFrame](common::command::ErrorCode::InvalidFrame)
+    /// error and keep reading
+    Reply,
+}
+
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionSettings {
+    pub config: ConnectionConfig,
+    pub malformed_frame_policy: MalformedFramePolicy,
+    /// Switch to MessagePack when the client offers it
+    pub allow_message_pack: bool,
+    /// Accept `tcp://` endpoints, for local development only
+    pub allow_plaintext: bool,
+    /// Clients served at the same time, others wait to be accepted
+    pub max_connections: usize,
+    /// Time a client gets to finish the TLS and protocol handshakes
+    pub handshake_timeout: Option<Duration>,
+}
+
+impl ConnectionSettings {
+    fn load(source: &ConfigSource) -> anyhow::Result<Self> {
+        let malformed_frame_policy = match source.value("connection", "malformed frame policy") {
+            Some("drop") => MalformedFramePolicy::Drop,
+            Some("reply") | None => MalformedFramePolicy::Reply,
+            Some(_) => {
+                return Err(source.invalid("connection", "malformed frame policy", "'drop' or 'reply'"));
+            }
+        };
+        let max_connections = source
+            .parse("connection", "max connections", "a positive number")?
+            .unwrap_or(DEFAULT_MAX_CONNECTIONS);
+        if max_connections == 0 {
+            return Err(source.invalid("connection", "max connections", "a positive number"));
+        }
+        let keepalive_interval = source.seconds(
+            "connection",
+            "keepalive interval",
+            Some(DEFAULT_KEEPALIVE_INTERVAL)
+        )?;
+        let idle_timeout = source.seconds("connection", "idle timeout", Some(DEFAULT_IDLE_TIMEOUT))?;
+        // Clients would be dropped for being idle before they are pinged
+        if let (Some(keepalive_interval), Some(idle_timeout)) = (keepalive_interval, idle_timeout) {
+            if keepalive_interval >= idle_timeout {
+                anyhow::bail!(
+                    "Invalid [connection] keepalive interval of {}s, it must be shorter than the idle timeout of {}s",
+                    keepalive_interval.as_secs(),
+                    idle_timeout.as_secs()
+                );
+            }
+        }
+        Ok(Self {
+            config: ConnectionConfig {
+                max_frame_length: source
+                    .parse("connection", "max frame length", "a number of bytes")?
+                    .unwrap_or(DEFAULT_MAX_FRAME_LENGTH),
+                keepalive_interval,
+                idle_timeout,
+            },
+            malformed_frame_policy,
+            allow_message_pack: source.flag("connection", "allow message pack", true)?,
+            allow_plaintext: source.flag("connection", "allow plaintext", false)?,
+            max_connections,
+            handshake_timeout: source.seconds(
+                "connection",
+                "handshake timeout",
+                Some(DEFAULT_HANDSHAKE_TIMEOUT)
+            )?,
+        })
+    }
+}
+
+/// How the confirmation emails are sent
+#[derive(Clone)]
+pub struct MailConfig {
+    pub backend: MailBackend,
+    pub sender: Sender,
+}
+
+impl MailConfig {
+    /// `None` when `[mail] backend` is left empty
+    fn load(source: &ConfigSource) -> anyhow::Result<Option<Self>> {
+        let backend = match source.value("mail", "backend") {
+            None => {
+                return Ok(None);
+            }
+            Some("mailgun") =>
+                MailBackend::Mailgun {
+                    domain: source.required("mailgun", "domain")?.to_string(),
+                    api_key: source.required("mailgun", "api key")?.to_string(),
+                    eu: match source.value("mailgun", "region") {
+                        Some("eu") | None => true,
+                        Some("us") => false,
+                        Some(_) => {
+                            return Err(source.invalid("mailgun", "region", "'eu' or 'us'"));
+                        }
+                    },
+                },
+            Some("smtp") => {
+                let security = match source.value("smtp", "security") {
+                    Some("starttls") | None => SmtpSecurity::StartTls,
+                    Some("tls") => SmtpSecurity::Tls,
+                    Some("none") => SmtpSecurity::None,
+                    Some(_) => {
+                        return Err(source.invalid("smtp", "security", "'starttls', 'tls' or 'none'"));
+                    }
+                };
+                let credentials = match
+                    (source.value("smtp", "username"), source.value("smtp", "password"))
+                {
+                    (Some(username), Some(password)) => {
+                        Some((username.to_string(), password.to_string()))
+                    }
+                    (None, None) => None,
+                    (Some(_), None) => source.required("smtp", "password").map(|_| None)?,
+                    (None, Some(_)) => source.required("smtp", "username").map(|_| None)?,
+                };
+                MailBackend::Smtp {
+                    host: source.required("smtp", "host")?.to_string(),
+                    port: source
+                        .parse("smtp", "port", "a port number")?
+                        .unwrap_or(security.default_port()),
+                    security,
+                    credentials,
+                }
+            }
+            Some("maildir") =>
+                MailBackend::Maildir {
+                    path: source.required("mail", "maildir path")?.into(),
+                },
+            Some("stdout") => MailBackend::Stdout,
+            Some(_) => {
+                return Err(source.invalid("mail", "backend", "'mailgun', 'smtp', 'maildir' or 'stdout'"));
+            }
+        };
+        Ok(
+            Some(Self {
+                backend,
+                sender: Sender {
+                    address: source.required("mail", "sender")?.to_string(),
+                    name: source.value("mail", "sender name").unwrap_or("termplay").to_string(),
+                },
+            })
+        )
+    }
+}
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 8;
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How emails that could not be delivered are retried
+#[derive(Debug, Clone, Copy)]
+pub struct OutboxSettings {
+    /// Deliveries tried before a message is given up on
+    pub max_attempts: u32,
+    /// Wait after the first failure, doubled after each of the next ones
+    pub retry_delay: Duration,
+    pub max_retry_delay: Duration,
+    /// Longest a delivery may take before it counts as failed
+    pub send_timeout: Duration,
+}
+
+impl OutboxSettings {
+    fn load(source: &ConfigSource) -> anyhow::Result<Self> {
+        let max_attempts = source
+            .parse("outbox", "max attempts", "a positive number")?
+            .unwrap_or(DEFAULT_MAX_ATTEMPTS);
+        if max_attempts == 0 {
+            return Err(source.invalid("outbox", "max attempts", "a positive number"));
+        }
+        let delay = |key: &str, default: Duration| {
+            source
+                .seconds("outbox", key, Some(default))?
+                .ok_or_else(|| source.invalid("outbox", key, "a positive number of seconds"))
+        };
+        Ok(Self {
+            max_attempts,
+            retry_delay: delay("retry delay", DEFAULT_RETRY_DELAY)?,
+            max_retry_delay: delay("max retry delay", DEFAULT_MAX_RETRY_DELAY)?,
+            send_timeout: delay("send timeout", DEFAULT_SEND_TIMEOUT)?,
+        })
+    }
+}
+
+const DEFAULT_LINK_LIFETIME: Duration = Duration::from_secs(48 * 60 * 60);
+const DEFAULT_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long confirmation links work, and how often the registrations whose
+/// links expired are deleted
+#[derive(Debug, Clone, Copy)]
+pub struct ConfirmationSettings {
+    pub link_lifetime: Duration,
+    /// `None` when purging is disabled
+    pub purge_interval: Option<Duration>,
+}
+
+impl ConfirmationSettings {
+    fn load(source: &ConfigSource) -> anyhow::Result<Self> {
+        Ok(Self {
+            link_lifetime: source
+                .seconds("confirmation", "link lifetime", Some(DEFAULT_LINK_LIFETIME))?
+                .ok_or_else(|| {
+                    source.invalid("confirmation", "link lifetime", "a positive number of seconds")
+                })?,
+            purge_interval: source.seconds(
+                "confirmation",
+                "purge interval",
+                Some(DEFAULT_PURGE_INTERVAL)
+            )?,
+        })
+    }
+}
+
+/// Locale of the confirmation emails when the client does not ask for one
+const DEFAULT_LOCALE: &str = "fr";
+
+/// Where the confirmation server is reached from the links in the emails
+const DEFAULT_CONFIRMATION_URL: &str = "http://localhost:8000";
+
+fn load_templates(source: &ConfigSource) -> anyhow::Result<Templates> {
+    Templates::load(
+        source.value("mail", "templates path").map(Path::new),
+        source.value("mail", "default locale").unwrap_or(DEFAULT_LOCALE)
+    )
+}
+
+/// The validated server configuration
+#[derive(Clone)]
+pub struct Config {
+    /// Confirmation emails wait in the outbox until it is configured
+    pub mail: Option<MailConfig>,
+    pub templates: Templates,
+    /// Base URL of the confirmation server, without the trailing slash
+    pub confirmation_url: String,
+    /// Only loaded when serving TLS
+    pub tls: Option<TlsSettings>,
+    pub tls_reload_interval: Option<Duration>,
+    pub database_path: PathBuf,
+    pub connection: ConnectionSettings,
+    pub session_lifetime: Duration,
+    pub outbox: OutboxSettings,
+    pub confirmation: ConfirmationSettings,
+}
+
+impl Config {
+    /// Check every setting, reporting all the problems at once
+    pub fn load(source: &ConfigSource, load_tls: bool) -> anyhow::Result<Self> {
+        let mut errors = Vec::new();
+        let mail = check(&mut errors, MailConfig::load(source));
+        let templates = check(&mut errors, load_templates(source));
+        let confirmation_url = source
+            .value("mail", "confirmation url")
+            .unwrap_or(DEFAULT_CONFIRMATION_URL)
+            .trim_end_matches('/')
+            .to_string();
+        let tls = check(&mut errors, match load_tls {
+            true => TlsSettings::load(source).map(Some),
+            false => Ok(None),
+        });
+        let tls_reload_interval = check(
+            &mut errors,
+            source.seconds("ssl", "reload interval", Some(tls::DEFAULT_RELOAD_INTERVAL))
+        );
+        let database_path = check(
+            &mut errors,
+            source.required("database", "path").map(PathBuf::from)
+        );
+        let connection = check(&mut errors, ConnectionSettings::load(source));
+        let session_lifetime = check(
+            &mut errors,
+            source
+                .seconds("sessions", "lifetime", None)
+                .map(|lifetime| lifetime.unwrap_or(Duration::from_secs(DEFAULT_SESSION_LIFETIME_SECS)))
+        );
+
+        let outbox = check(&mut errors, OutboxSettings::load(source));
+        let confirmation = check(&mut errors, ConfirmationSettings::load(source));
+
+        match
+            (
+                mail,
+                templates,
+                tls,
+                tls_reload_interval,
+                database_path,
+                connection,
+                session_lifetime,
+                outbox,
+                confirmation,
+            )
+        {
+            (
+                Some(mail),
+                Some(templates),
+                Some(tls),
+                Some(tls_reload_interval),
+                Some(database_path),
+                Some(connection),
+                Some(session_lifetime),
+                Some(outbox),
+                Some(confirmation),
+            ) =>
+                Ok(Self {
+                    mail,
+                    templates,
+                    confirmation_url,
+                    tls,
+                    tls_reload_interval,
+                    database_path,
+                    connection,
+                    session_lifetime,
+                    outbox,
+                    confirmation,
+                }),
+            _ => anyhow::bail!("Invalid configuration:\n  - {}", errors.join("\n  - ")),
+        }
+    }
+}
+
+fn check<T>(errors: &mut Vec<String>, result: anyhow::Result<T>) -> Option<T> {
+    result.map_err(|e| errors.push(format!("{:#}", e))).ok()
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "database: {}", self.database_path.display())?;
+        match &self.tls {
+            Some(tls) => writeln!(f, "tls: {}", tls)?,
+            None => writeln!(f, "tls: not used")?,
+        }
+        match &self.mail {
+            Some(mail) => writeln!(f, "mail: {} as {}", mail.backend, mail.sender.address)?,
+            None => writeln!(f, "mail: not configured, registration emails cannot be sent")?,
+        }
+        writeln!(f, "email templates: {}", self.templates)?;
+        writeln!(
+            f,
+            "confirmation links: {}/confirm/..., valid for {}s",
+            self.confirmation_url,
+            self.confirmation.link_lifetime.as_secs()
+        )?;
+        writeln!(
+            f,
+            "email retries: {} attempts, {}s to {}s apart",
+            self.outbox.max_attempts,
+            self.outbox.retry_delay.as_secs(),
+            self.outbox.max_retry_delay.as_secs()
+        )?;
+        writeln!(f, "max connections: {}", self.connection.max_connections)?;
+        write!(f, "session lifetime: {}s", self.session_lifetime.as_secs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection_settings(settings: &[&str]) -> anyhow::Result<ConnectionSettings> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("termplay.ini");
+        std::fs::write(&path, "").unwrap();
+        let overrides = settings
+            .iter()
+            .map(|setting| setting.parse())
+            .collect::<anyhow::Result<Vec<Override>>>()?;
+        ConnectionSettings::load(&ConfigSource::load(&path, &overrides)?)
+    }
+
+    #[test]
+    fn keepalive_interval_must_be_shorter_than_the_idle_timeout() {
+        assert!(connection_settings(&[]).is_ok());
+        assert!(connection_settings(&["connection.keepalive interval=45"]).is_err());
+        assert!(connection_settings(&["connection.idle timeout=10"]).is_err());
+        assert!(
+            connection_settings(&["connection.keepalive interval=5", "connection.idle timeout=10"]).is_ok()
+        );
+        // Either one turned off
+        assert!(connection_settings(&["connection.keepalive interval=0", "connection.idle timeout=10"]).is_ok());
+        assert!(connection_settings(&["connection.keepalive interval=60", "connection.idle timeout=0"]).is_ok());
+    }
+}
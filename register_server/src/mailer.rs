@@ -0,0 +1,231 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use lettre::message::{ Mailbox, MultiPart };
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{ AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor };
+use mailgun_rs::{ EmailAddress, Mailgun, MailgunRegion };
+use rand::RngCore;
+
+/// An email ready to be handed to a [Mailer]
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub html: String,
+    /// Shown by clients that do not display HTML
+    pub text: String,
+}
+
+/// Something that delivers emails, picked by `[mail] backend`
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: &Email) -> anyhow::Result<()>;
+}
+
+/// Who the emails are sent from
+#[derive(Debug, Clone)]
+pub struct Sender {
+    pub address: String,
+    pub name: String,
+}
+
+impl Sender {
+    fn mailbox(&self) -> anyhow::Result<Mailbox> {
+        format!("{} <{}>", self.name, self.address)
+            .parse()
+            .with_context(|| format!("Invalid sender address '{}'", self.address))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmtpSecurity {
+    /// Plain text, only meant for a local SMTP sink
+    None,
+    StartTls,
+    /// TLS from the first byte, usually on port 465
+    Tls,
+}
+
+impl SmtpSecurity {
+    pub fn default_port(self) -> u16 {
+        match self {
+            SmtpSecurity::None => 25,
+            SmtpSecurity::StartTls => 587,
+            SmtpSecurity::Tls => 465,
+        }
+    }
+}
+
+/// Where emails go, with what each backend needs
+#[derive(Clone)]
+pub enum MailBackend {
+    Mailgun {
+        domain: String,
+        api_key: String,
+        /// `true` for the EU API endpoint
+        eu: bool,
+    },
+    Smtp {
+        host: String,
+        port: u16,
+        security: SmtpSecurity,
+        credentials: Option<(String, String)>,
+    },
+    /// Each message is written to a Maildir, for development and tests
+    Maildir {
+        path: PathBuf,
+    },
+    /// Each message is printed on the standard output
+    Stdout,
+}
+
+/// Never shows the API key or the SMTP password
+impl fmt::Display for MailBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MailBackend::Mailgun { domain, eu, .. } => {
+                write!(f, "Mailgun {} ({})", domain, if *eu { "EU" } else { "US" })
+            }
+            MailBackend::Smtp { host, port, security, .. } => {
+                write!(f, "SMTP {}:{} ({:?})", host, port, security)
+            }
+            MailBackend::Maildir { path } => write!(f, "Maildir '{}'", path.display()),
+            MailBackend::Stdout => write!(f, "standard output"),
+        }
+    }
+}
+
+pub fn create(backend: &MailBackend, sender: &Sender) -> anyhow::Result<Box<dyn Mailer>> {
+    Ok(match backend.clone() {
+        MailBackend::Mailgun { domain, api_key, eu } =>
+            Box::new(MailgunMailer {
+                domain,
+                api_key,
+                eu,
+                sender: sender.clone(),
+            }),
+        MailBackend::Smtp { host, port, security, credentials } => {
+            let builder = match security {
+                SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&host),
+                SmtpSecurity::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)?,
+                SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&host)?,
+            };
+            let mut builder = builder.port(port);
+            if let Some((username, password)) = credentials {
+                builder = builder.credentials(Credentials::new(username, password));
+            }
+            Box::new(SmtpMailer {
+                transport: builder.build(),
+                from: sender.mailbox()?,
+            })
+        }
+        MailBackend::Maildir { path } => Box::new(MaildirMailer { path, from: sender.mailbox()? }),
+        MailBackend::Stdout => Box::new(StdoutMailer { from: sender.mailbox()? }),
+    })
+}
+
+fn build_message(from: &Mailbox, email: &Email) -> anyhow::Result<Message> {
+    Ok(
+        Message::builder()
+            .from(from.clone())
+            .to(email.to.parse().with_context(|| format!("Invalid recipient '{}'", email.to))?)
+            .subject(email.subject.clone())
+            .multipart(MultiPart::alternative_plain_html(email.text.clone(), email.html.clone()))?
+    )
+}
+
+struct MailgunMailer {
+    domain: String,
+    api_key: String,
+    eu: bool,
+    sender: Sender,
+}
+
+#[async_trait]
+impl Mailer for MailgunMailer {
+    async fn send(&self, email: &Email) -> anyhow::Result<()> {
+        let message = mailgun_rs::Message {
+            to: vec![EmailAddress::address(&email.to)],
+            subject: email.subject.clone(),
+            html: email.html.clone(),
+            text: email.text.clone(),
+            ..Default::default()
+        };
+        let client = Mailgun {
+            api_key: self.api_key.clone(),
+            domain: self.domain.clone(),
+            message,
+        };
+        let region = match self.eu {
+            true => MailgunRegion::EU,
+            false => MailgunRegion::US,
+        };
+        let sender = EmailAddress::name_address(&self.sender.name, &self.sender.address);
+        client.async_send(region, &sender).await?;
+        Ok(())
+    }
+}
+
+struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &Email) -> anyhow::Result<()> {
+        self.transport.send(build_message(&self.from, email)?).await?;
+        Ok(())
+    }
+}
+
+struct MaildirMailer {
+    path: PathBuf,
+    from: Mailbox,
+}
+
+#[async_trait]
+impl Mailer for MaildirMailer {
+    /// Written to `tmp` first then moved to `new`, so readers never see half
+    /// a message
+    async fn send(&self, email: &Email) -> anyhow::Result<()> {
+        let message = build_message(&self.from, email)?.formatted();
+        for dir in ["tmp", "new", "cur"] {
+            tokio::fs
+                ::create_dir_all(self.path.join(dir)).await
+                .with_context(|| format!("Could not create Maildir '{}'", self.path.display()))?;
+        }
+        let mut unique = [0u8; 8];
+        rand::rngs::OsRng.fill_bytes(&mut unique);
+        let name = format!(
+            "{}.{}.termplay",
+            database::unix_now(),
+            unique
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>()
+        );
+        let tmp = self.path.join("tmp").join(&name);
+        tokio::fs
+            ::write(&tmp, message).await
+            .with_context(|| format!("Could not write '{}'", tmp.display()))?;
+        tokio::fs::rename(&tmp, self.path.join("new").join(&name)).await?;
+        Ok(())
+    }
+}
+
+struct StdoutMailer {
+    from: Mailbox,
+}
+
+#[async_trait]
+impl Mailer for StdoutMailer {
+    async fn send(&self, email: &Email) -> anyhow::Result<()> {
+        let message = build_message(&self.from, email)?.formatted();
+        println!("----- email to {} -----\n{}\n-----", email.to, String::from_utf8_lossy(&message));
+        Ok(())
+    }
+}
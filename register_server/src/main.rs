@@ -1,62 +1,98 @@
 use core::result::Result::Ok;
+use anyhow::Context;
+use clap::{ Parser, Subcommand };
+use common::codec::Codec;
+use common::connection::ReceiveError;
+use common::envelope::Envelope;
 use common::server::CommandManager;
-use common::command::{ RegisterCommand, RegisterResponseCommand, ServerCommand, UserCommand };
-
-use mailgun_rs::{ EmailAddress, Mailgun, MailgunRegion, Message };
-use tokio::net::{ TcpListener, TcpStream };
-use tokio_native_tls::{ native_tls, TlsAcceptor };
-use std::collections::HashMap;
-use std::{ any, env };
-
-use std::fs::File;
-use std::io::Read;
-
-use native_tls::Identity;
-use rusqlite::{ Connection, Result, ToSql };
-use uuid::Uuid;
-use bcrypt::{ hash, hash_with_salt, DEFAULT_COST };
-
-async fn send_email(
-    domain: String,
-    key: String,
-    sender: String,
-    sender_name: String,
-    recipient: String,
-    subject: String,
-    body: String
-) -> anyhow::Result<()> {
-    let recipient = EmailAddress::address(recipient.as_str());
-    let message = Message {
-        to: vec![recipient],
-        subject: String::from(subject),
-        html: String::from(body),
-        ..Default::default()
-    };
+use common::transport::{ BoxedStream, Endpoint, Listener };
+use common::command::{
+    ErrorCode,
+    ErrorCommand,
+    HandshakeRejectedCommand,
+    HelloCommand,
+    ServerCommand,
+    UserCommand,
+    WelcomeCommand,
+    MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
 
-    let client = Mailgun {
-        api_key: String::from(key),
-        domain: String::from(domain),
-        message,
-    };
-    let sender = EmailAddress::name_address(sender_name.as_str(), sender.as_str());
+use tokio::io::{ AsyncRead, AsyncWrite };
+use tokio::sync::{ watch, Semaphore };
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
-    client.async_send(MailgunRegion::EU, &sender).await?;
-    Ok(())
+use rusqlite::Connection;
+
+mod account;
+mod client_session;
+mod config;
+mod mailer;
+mod outbox;
+mod session;
+mod templates;
+mod tls;
+
+use client_session::ClientSession;
+use config::{ Config, ConfigSource, ConnectionSettings, MalformedFramePolicy, Override };
+use outbox::OutboxNotifier;
+use session::SessionRegistry;
+use templates::TemplateContext;
+use tls::TlsAcceptor;
+
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// termplay account server
+#[derive(Parser)]
+#[command(version, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// ini configuration file
+    #[arg(required = true)]
+    config: Option<PathBuf>,
+    /// Where to listen: tls://host:port, tcp://host:port or unix:///path
+    #[arg(required_unless_present = "check_config")]
+    endpoint: Option<Endpoint>,
+    /// Override a setting, e.g. --set "connection.max connections=64". Settings
+    /// can also be given as TERMPLAY_<SECTION>_<KEY> environment variables
+    #[arg(long = "set", value_name = "SECTION.KEY=VALUE")]
+    overrides: Vec<Override>,
+    /// Validate the configuration and exit
+    #[arg(long)]
+    check_config: bool,
 }
 
-async fn send_email_configured(
-    conf: HashMap<String, HashMap<String, Option<String>>>,
-    recipient: String
-) -> anyhow::Result<()> {
-    let domain = conf["mailgun"]["domain"].clone().unwrap();
-    let key = conf["mailgun"]["api key"].clone().unwrap();
-    let sender = conf["mailgun"]["sender"].clone().unwrap();
-    let sender_name = conf["mailgun"]["sender name"].clone().unwrap();
-    let subject = conf["mailgun"]["subject"].clone().unwrap();
-    let body = conf["mailgun"]["body"].clone().unwrap();
-
-    print!("Envoi de l'email à {}...", recipient);
-    send_email(domain, key, sender, sender_name, recipient, subject, body).await?;
+#[derive(Subcommand)]
+enum Command {
+    /// Print the confirmation email as it would be sent, to preview templates
+    RenderEmail {
+        /// ini configuration file
+        config: PathBuf,
+        /// Override a setting, as when serving
+        #[arg(long = "set", value_name = "SECTION.KEY=VALUE")]
+        overrides: Vec<Override>,
+        /// Language of the email, the default locale when left out
+        #[arg(long)]
+        locale: Option<String>,
+        /// Login of the made-up account the email is sent to
+        #[arg(long, default_value = "alice")]
+        login: String,
+    },
+}
+
+fn render_email(config: &Config, locale: Option<&str>, login: &str) -> anyhow::Result<()> {
+    let email = config.templates.render(
+        locale,
+        format!("{}@example.com", login),
+        &TemplateContext::example(login, &config.confirmation_url)
+    )?;
+    println!("Locale: {}", config.templates.resolve(locale));
+    println!("Subject: {}", email.subject);
+    println!("\n----- HTML -----\n{}", email.html.trim_end());
+    println!("\n----- text -----\n{}", email.text.trim_end());
     Ok(())
 }
 
@@ -64,188 +100,308 @@ async fn send_email_configured(
 extern crate ini;
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    if env::args().len() < 3 {
-        eprintln!("Usage: {} <config file path> <bind host>", env::args().next().unwrap());
-        std::process::exit(1);
+    let args = Args::parse();
+    if let Some(Command::RenderEmail { config, overrides, locale, login }) = args.command {
+        let source = ConfigSource::load(&config, &overrides)?;
+        return render_email(&Config::load(&source, false)?, locale.as_deref(), &login);
     }
+    let config_path = args.config.context("Missing configuration file")?;
 
-    let conf_file_path = env::args().nth(1).unwrap();
-    let conf = ini!(conf_file_path.as_str());
-
-    let cert_file_path = conf["ssl"]["cert file path"].clone().unwrap();
-    let key_file_path = conf["ssl"]["key file path"].clone().unwrap();
-
-    println!("Loading cerfiticate and private key files");
-    let mut cert_file = File::open(cert_file_path)?;
-    let mut key_file = File::open(key_file_path)?;
-
-    let mut key_buffer = Vec::new();
-    let mut cert_buffer = Vec::new();
-    cert_file.read_to_end(&mut cert_buffer)?;
-    key_file.read_to_end(&mut key_buffer)?;
+    let source = ConfigSource::load(&config_path, &args.overrides)?;
+    // Without an endpoint, the [ssl] section is checked when it is filled in
+    let load_tls = match &args.endpoint {
+        Some(endpoint) => endpoint.is_tls(),
+        None => source.value("ssl", "cert file path").is_some(),
+    };
+    let config = Config::load(&source, load_tls)?;
+    if args.check_config {
+        println!("{} is valid\n{}", config_path.display(), config);
+        return Ok(());
+    }
+    let endpoint = args.endpoint.context("Missing endpoint")?;
+    let mailer = match &config.mail {
+        Some(mail) => {
+            let mailer = mailer::create(&mail.backend, &mail.sender)?;
+            println!("Sending emails through {}", mail.backend);
+            Some(mailer)
+        }
+        None => {
+            eprintln!("Warning: [mail] is not configured, registration emails stay in the outbox");
+            None
+        }
+    };
+    let mut conn = create_get_db_connection(&config)?;
+    database::migrations::migrate(&mut conn)?;
+    drop(conn);
+    let config = Arc::new(config);
+    let settings = config.connection;
+    let sessions = SessionRegistry::new();
+    let outbox = OutboxNotifier::new();
+    if let Some(mailer) = mailer {
+        tokio::spawn(outbox::run(config.clone(), mailer, outbox.clone()));
+    }
+    if let Some(interval) = config.confirmation.purge_interval {
+        tokio::spawn(account::purge_expired_registrations(config.clone(), interval));
+    }
 
-    println!("Configuring TLS acceptor with certificate and private key");
-    let acceptor = {
-        let identity = Identity::from_pkcs12(&cert_buffer, "")?;
-        let builder = native_tls::TlsAcceptor::new(identity)?;
-        TlsAcceptor::from(builder)
+    let acceptor = match endpoint.is_tls() {
+        true => {
+            let tls_settings = config.tls.clone().context("Missing [ssl] settings")?;
+            println!("Loading TLS identity from {}", tls_settings);
+            let acceptor = tls::create_acceptor(&tls_settings)?;
+            let (acceptor_sender, acceptor_receiver) = watch::channel(acceptor);
+            tokio::spawn(tls::reload(tls_settings, acceptor_sender, config.tls_reload_interval));
+            Some(acceptor_receiver)
+        }
+        false => {
+            println!("Serving {} without TLS", endpoint);
+            None
+        }
     };
 
     println!("Starting server...");
-    let addr = env::args().nth(2).unwrap();
-    let listener = TcpListener::bind(&addr).await?;
-    println!("Server started on {}", addr);
+    let listener = Listener::bind(&endpoint, settings.allow_plaintext).await?;
+    println!("Server started on {}", endpoint);
 
     println!("Listening for incoming connections");
+    let connection_slots = Arc::new(Semaphore::new(settings.max_connections));
     loop {
-        let (socket, _) = listener.accept().await?;
-        let acceptor = acceptor.clone();
+        let permit = match connection_slots.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                println!(
+                    "{} clients connected, waiting for one to leave before accepting more",
+                    settings.max_connections
+                );
+                connection_slots.clone().acquire_owned().await?
+            }
+        };
+        let (socket, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // Usually out of file descriptors, give clients a chance to leave
+                eprintln!("Could not accept a connection: {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+        println!("New connection from {}", peer);
+        // Taken per connection so a reloaded certificate applies from now on
+        let acceptor = acceptor.as_ref().map(|receiver| receiver.borrow().clone());
 
-        let conf_cloned = conf.clone();
-        // tokio::spawn(async move {
-        if let Err(e) = handle_connection(conf_cloned, socket, acceptor).await {
-            eprintln!("Error handling connection: {}", e);
-        }
-        // });
+        let config = config.clone();
+        let sessions = sessions.clone();
+        let outbox = outbox.clone();
+        tokio::spawn(async move {
+            let handled = handle_connection(config, settings, sessions, outbox, socket, acceptor).await;
+            if let Err(e) = handled {
+                eprintln!("Error handling connection from {}: {:#}", peer, e);
+            }
+            drop(permit);
+        });
     }
 }
 
 async fn handle_connection(
-    conf: HashMap<String, HashMap<String, Option<String>>>,
-    socket: TcpStream,
-    acceptor: TlsAcceptor
+    config: Arc<Config>,
+    settings: ConnectionSettings,
+    sessions: SessionRegistry,
+    outbox: OutboxNotifier,
+    socket: BoxedStream,
+    acceptor: Option<TlsAcceptor>
 ) -> anyhow::Result<()> {
-    println!("Accepting TLS connection...");
-    let tls_stream = acceptor.accept(socket).await.ok().unwrap();
-
-    println!("TLS connection accepted");
+    let establishing = establish(settings, socket, acceptor);
+    let established = match settings.handshake_timeout {
+        Some(handshake_timeout) =>
+            tokio::time::timeout(handshake_timeout, establishing).await.map_err(|_| {
+                anyhow::anyhow!("Handshake not completed within {:?}", handshake_timeout)
+            })??,
+        None => establishing.await?,
+    };
+    let (cmd_manager, certificate_login) = match established {
+        Some(established) => established,
+        None => {
+            return Ok(());
+        }
+    };
+    let policy = settings.malformed_frame_policy;
 
-    let stream = tokio::io::BufStream::new(tls_stream);
-    let mut cmd_manager = CommandManager::new(stream);
+    ClientSession::new(config, sessions, outbox, cmd_manager, policy, certificate_login).run().await
+}
 
-    println!("Waiting for command...");
-    match cmd_manager.receive::<UserCommand>().await {
-        Ok(UserCommand::Register(RegisterCommand { login, password })) => {
-            println!("Registering user {} with password {}", login, password);
-            match register(conf, login, password).await {
-                Ok(_res) => {
-                    println!("Email sent successfully");
-                }
-                Err(e) => {
-                    eprintln!("Error sending email: {}", e);
-                }
+/// Run the TLS handshake, if any, then the protocol handshake.
+///
+/// Returns the connection with the login named by the client certificate, or
+/// `None` when the client was rejected or left.
+async fn establish(
+    settings: ConnectionSettings,
+    socket: BoxedStream,
+    acceptor: Option<TlsAcceptor>
+) -> anyhow::Result<Option<(CommandManager<BoxedStream>, Option<String>)>> {
+    let (stream, certificate_login): (BoxedStream, _) = match acceptor {
+        Some(acceptor) => {
+            println!("Accepting TLS connection...");
+            let (tls_stream, certificate_login) = acceptor
+                .accept(socket).await
+                .map_err(|e| anyhow::anyhow!("TLS handshake failed: {:#}", e))?;
+            match &certificate_login {
+                Some(login) => println!("TLS connection accepted, client certificate for {}", login),
+                None => println!("TLS connection accepted"),
             }
-            cmd_manager.send(
-                &ServerCommand::RegisterResponse(RegisterResponseCommand {
-                    email_sent: true,
-                })
-            ).await?;
+            (Box::new(tls_stream), certificate_login)
         }
-        _ => {
-            println!("Unknown command received");
+        None => (socket, None),
+    };
+
+    let mut cmd_manager = CommandManager::with_config(stream, settings.config);
+
+    println!("Waiting for hello...");
+    let hello = match handshake(&mut cmd_manager, settings).await? {
+        Some(hello) => hello,
+        None => {
+            return Ok(None);
         }
-    }
-    Ok(())
+    };
+    println!(
+        "Client {} {} speaks protocol version {} using {:?}",
+        hello.client_name,
+        hello.client_version,
+        hello.protocol_version,
+        cmd_manager.codec()
+    );
+    Ok(Some((cmd_manager, certificate_login)))
 }
 
-async fn register(
-    conf: HashMap<String, HashMap<String, Option<String>>>,
-    login: String,
-    password: String
-) -> anyhow::Result<()> {
-    let conn = create_get_db_connection(conf)?;
-    // Create the pre_register table if it doesn't exist
-    match
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS pre_register (id TEXT PRIMARY KEY, login TEXT, password TEXT, salt TEXT)",
-            []
-        )
-    {
-        Ok(_) => {
-            println!("Table pre_register created successfully");
-        }
-        Err(e) => {
-            eprintln!("Error creating table pre_register: {}", e);
-            Err(e)?;
+/// Receive the next command, applying `policy` to malformed frames.
+///
+/// Returns `None` when the client closed the connection cleanly.
+async fn receive_command<S>(
+    cmd_manager: &mut CommandManager<S>,
+    policy: MalformedFramePolicy
+) -> anyhow::Result<Option<Envelope<UserCommand>>>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    loop {
+        match cmd_manager.receive().await {
+            Ok(command) => {
+                return Ok(Some(command));
+            }
+            Err(ReceiveError::Closed) => {
+                return Ok(None);
+            }
+            Err(e) if e.is_bad_frame() && policy == MalformedFramePolicy::Reply => {
+                eprintln!("Invalid frame received: {}", e);
+                cmd_manager.send(
+                    &Envelope::reply(
+                        None,
+                        ServerCommand::Error(ErrorCommand::new(ErrorCode::InvalidFrame, e.to_string()))
+                    )
+                ).await?;
+            }
+            Err(e) => {
+                return Err(e.into());
+            }
         }
     }
+}
 
-    // Generate a UUID for the registration
-    let id = Uuid::new_v4().to_string();
-
-    // Generate a salt for the password
-    let salt = Uuid::new_v4().to_bytes_le();
-    match salt.to_sql()? {
-        rusqlite::types::ToSqlOutput::Borrowed(_bytes) => {
-            println!("Salt: {:?}", salt);
-        }
-        rusqlite::types::ToSqlOutput::Owned(_bytes) => {
-            println!("Salt: {:?}", salt);
+/// Read the client's hello and answer with a welcome or a rejection.
+///
+/// Returns `None` when the client was rejected, in which case the connection
+/// should be closed without reading anything else.
+async fn handshake<S>(
+    cmd_manager: &mut CommandManager<S>,
+    settings: ConnectionSettings
+) -> anyhow::Result<Option<HelloCommand>>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    let request = match receive_command(cmd_manager, settings.malformed_frame_policy).await? {
+        Some(request) => request,
+        None => {
+            return Ok(None);
         }
-        _ => {
-            eprintln!("Error converting salt to SQL");
-            Err(anyhow::Error::msg("Error converting salt to SQL"))?;
+    };
+    let rejection = match request.command {
+        UserCommand::Hello(hello) => {
+            if
+                (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&hello.protocol_version)
+            {
+                let mut codecs = vec![Codec::Json];
+                if settings.allow_message_pack {
+                    codecs.push(Codec::MessagePack);
+                }
+                let codec = hello
+                    .offered_codecs()
+                    .into_iter()
+                    .find(|codec| codecs.contains(codec))
+                    .unwrap_or_default();
+                cmd_manager.send(
+                    &Envelope::reply(
+                        request.id,
+                        ServerCommand::Welcome(WelcomeCommand {
+                            protocol_version: hello.protocol_version,
+                            server_name: env!("CARGO_PKG_NAME").to_string(),
+                            server_version: env!("CARGO_PKG_VERSION").to_string(),
+                            capabilities: codecs
+                                .iter()
+                                .map(|codec| codec.capability().to_string())
+                                .collect(),
+                            codec,
+                        })
+                    )
+                ).await?;
+                cmd_manager.set_codec(codec);
+                return Ok(Some(hello));
+            }
+            format!(
+                "protocol version {} is not supported, this server speaks versions {} to {}. Please update {}",
+                hello.protocol_version,
+                MIN_PROTOCOL_VERSION,
+                PROTOCOL_VERSION,
+                hello.client_name
+            )
         }
-    }
+        _ => String::from("the first command must be a hello"),
+    };
 
-    // Hash the password with a salt
-    match hash_with_salt(password, DEFAULT_COST, salt) {
-        Ok(hashed_password) => {
-            println!("Hashed password: {}", hashed_password.to_string());
-            // Insert the registration data into the pre_register table
-            conn.execute(
-                "INSERT INTO pre_register (id, login, password, salt) VALUES (?1, ?2, ?3, ?4)",
-                [id, login, hashed_password.to_string()]
-            )?;
-        }
-        Err(e) => {
-            eprintln!("Error hashing password: {}", e);
-            Err(e)?;
-        }
+    println!("Rejecting client: {}", rejection);
+    cmd_manager.send(
+        &Envelope::reply(
+            request.id,
+            ServerCommand::HandshakeRejected(HandshakeRejectedCommand {
+                min_protocol_version: MIN_PROTOCOL_VERSION,
+                max_protocol_version: PROTOCOL_VERSION,
+                reason: rejection,
+            })
+        )
+    ).await?;
+    Ok(None)
+}
+
+/// Log `e` on the server side and turn it into the error sent to the client.
+///
+/// `message` is what the client gets to see, the details stay in the logs.
+fn report<E: std::fmt::Display>(
+    code: ErrorCode,
+    message: &'static str
+) -> impl FnOnce(E) -> ErrorCommand {
+    move |e| {
+        eprintln!("{}: {}", message, e);
+        ErrorCommand::new(code, message)
     }
+}
 
-    Ok(())
+fn create_get_db_connection(config: &Config) -> anyhow::Result<Connection> {
+    Ok(database::open(&config.database_path)?)
 }
 
-fn create_get_db_connection(
-    conf: HashMap<String, HashMap<String, Option<String>>>
-) -> anyhow::Result<Connection> {
-    match conf.get("database") {
-        Some(db_conf) => {
-            match db_conf.get("path") {
-                Some(path) => {
-                    match path {
-                        Some(path) => { Ok(Connection::open(path)?) }
-                        None => {
-                            anyhow::bail!("Database path not found in configuration");
-                        }
-                    }
-                }
-                None => {
-                    anyhow::bail!("Database path not found in configuration");
-                }
-            }
-        }
-        None => {
-            anyhow::bail!("Database configuration not found");
-        }
+/// Run `work` on the blocking thread pool. bcrypt and SQLite take long
+/// enough to hold up every other connection served by the same worker.
+async fn run_blocking<T, E>(work: impl (FnOnce() -> Result<T, E>) + Send + 'static) -> Result<T, E>
+    where T: Send + 'static, E: From<ErrorCommand> + Send + 'static
+{
+    match tokio::task::spawn_blocking(work).await {
+        Ok(result) => result,
+        Err(e) => Err(report(ErrorCode::Internal, "The request could not be completed")(e).into()),
     }
 }
-
-// Ok(UserCommand::Register(RegisterCommand { login, password })) => {
-//     println!(
-//         "Enregistrement de l'utilisateur {} avec le mot de passe {}",
-//         login,
-//         password
-//     );
-//     match send_email_configured(conf, login).await {
-//         _res => {
-//             println!("Email envoyé avec succès");
-//         }
-//     }
-//     cmd_manager.send(
-//         &ServerCommand::RegisterResponse(RegisterResponseCommand {
-//             email_sent: true,
-//         })
-//     ).await?;
-// }
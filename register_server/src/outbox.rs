@@ -0,0 +1,246 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use database::outbox::{ self, OutboxMessage };
+use database::unix_now;
+use rusqlite::Connection;
+use tokio::sync::Notify;
+
+use crate::config::{ Config, OutboxSettings };
+use crate::create_get_db_connection;
+use crate::mailer::{ Email, Mailer };
+
+/// Longest the worker sleeps without looking at the outbox, in case a
+/// message was queued by something that could not wake it up
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Messages read from the database at once
+const BATCH_SIZE: usize = 16;
+
+/// Wakes the delivery worker up when a message is queued
+#[derive(Debug, Clone, Default)]
+pub struct OutboxNotifier {
+    notify: Arc<Notify>,
+}
+
+impl OutboxNotifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn notify(&self) {
+        self.notify.notify_one();
+    }
+}
+
+/// Queue `email`, it is delivered once the transaction it is part of commits
+pub fn enqueue(conn: &Connection, email: &Email) -> rusqlite::Result<i64> {
+    outbox::enqueue(conn, &email.to, &email.subject, &email.html, &email.text)
+}
+
+/// Wait before the next attempt after `attempts` failed ones, doubling each
+/// time up to the maximum
+fn retry_delay(settings: &OutboxSettings, attempts: u32) -> Duration {
+    let factor = 1u32 << attempts.saturating_sub(1).min(31);
+    settings.retry_delay.saturating_mul(factor).min(settings.max_retry_delay)
+}
+
+/// Deliver queued emails for as long as the server runs
+pub async fn run(config: Arc<Config>, mailer: Box<dyn Mailer>, notifier: OutboxNotifier) {
+    loop {
+        let wait = match deliver_due(&config, mailer.as_ref()).await {
+            Ok(Some(next_attempt_at)) => {
+                Duration::from_secs(next_attempt_at.saturating_sub(unix_now())).min(POLL_INTERVAL)
+            }
+            Ok(None) => POLL_INTERVAL,
+            Err(e) => {
+                eprintln!("Could not go through the outbox: {:#}", e);
+                POLL_INTERVAL
+            }
+        };
+        tokio::select! {
+            _ = notifier.notify.notified() => {}
+            _ = tokio::time::sleep(wait) => {}
+        }
+    }
+}
+
+/// Run `work` against the database on the blocking thread pool, so SQLite
+/// does not hold up the runtime
+async fn with_database<T: Send + 'static>(
+    config: &Arc<Config>,
+    work: impl (FnOnce(&Connection) -> rusqlite::Result<T>) + Send + 'static
+) -> anyhow::Result<T> {
+    let config = config.clone();
+    tokio::task::spawn_blocking(move || Ok(work(&create_get_db_connection(&config)?)?)).await?
+}
+
+/// Try every message that is due once.
+///
+/// Returns when the next pending message is due, if there is one.
+async fn deliver_due(config: &Arc<Config>, mailer: &dyn Mailer) -> anyhow::Result<Option<u64>> {
+    loop {
+        let messages = with_database(config, |conn| outbox::due(conn, BATCH_SIZE)).await?;
+        if messages.is_empty() {
+            break;
+        }
+        for message in messages {
+            let settings = config.outbox;
+            let sent = tokio::time::timeout(settings.send_timeout, mailer.send(&email(&message))).await;
+            let result = sent.unwrap_or_else(|_| {
+                Err(anyhow::anyhow!("No answer after {}s", settings.send_timeout.as_secs()))
+            });
+            with_database(config, move |conn| record(conn, &settings, &message, result)).await?;
+        }
+    }
+    with_database(config, outbox::next_attempt_at).await
+}
+
+fn email(message: &OutboxMessage) -> Email {
+    Email {
+        to: message.recipient.clone(),
+        subject: message.subject.clone(),
+        html: message.html.clone(),
+        text: message.text.clone(),
+    }
+}
+
+fn record(
+    conn: &Connection,
+    settings: &OutboxSettings,
+    message: &OutboxMessage,
+    result: anyhow::Result<()>
+) -> rusqlite::Result<()> {
+    let attempts = message.attempts + 1;
+    match result {
+        Ok(()) => {
+            println!("Email {} delivered to {}", message.id, message.recipient);
+            outbox::mark_sent(conn, message.id)
+        }
+        Err(e) if attempts >= settings.max_attempts => {
+            eprintln!(
+                "Giving up on email {} to {} after {} attempts: {:#}",
+                message.id,
+                message.recipient,
+                attempts,
+                e
+            );
+            outbox::mark_dead(conn, message.id, &format!("{:#}", e))
+        }
+        Err(e) => {
+            let delay = retry_delay(settings, attempts);
+            eprintln!(
+                "Could not deliver email {} to {} (attempt {}), retrying in {}s: {:#}",
+                message.id,
+                message.recipient,
+                attempts,
+                delay.as_secs(),
+                e
+            );
+            outbox::retry_later(conn, message.id, &format!("{:#}", e), unix_now() + delay.as_secs())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::config::ConfigSource;
+
+    fn settings() -> OutboxSettings {
+        OutboxSettings {
+            max_attempts: 3,
+            retry_delay: Duration::from_secs(30),
+            max_retry_delay: Duration::from_secs(3600),
+            send_timeout: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_maximum() {
+        let settings = settings();
+
+        assert_eq!(retry_delay(&settings, 0), Duration::from_secs(30));
+        assert_eq!(retry_delay(&settings, 1), Duration::from_secs(30));
+        assert_eq!(retry_delay(&settings, 2), Duration::from_secs(60));
+        assert_eq!(retry_delay(&settings, 7), Duration::from_secs(1920));
+        assert_eq!(retry_delay(&settings, 8), Duration::from_secs(3600));
+        // Neither the shift nor the multiplication overflows
+        assert_eq!(retry_delay(&settings, 40), Duration::from_secs(3600));
+        assert_eq!(retry_delay(&settings, u32::MAX), Duration::from_secs(3600));
+    }
+
+    enum FailingMailer {
+        Hangs,
+        Fails,
+    }
+
+    #[async_trait]
+    impl Mailer for FailingMailer {
+        async fn send(&self, _email: &Email) -> anyhow::Result<()> {
+            match self {
+                FailingMailer::Hangs => std::future::pending().await,
+                FailingMailer::Fails => anyhow::bail!("Mailbox unavailable"),
+            }
+        }
+    }
+
+    fn config(dir: &Path) -> Arc<Config> {
+        let path = dir.join("termplay.ini");
+        fs::write(
+            &path,
+            format!(
+                "[database]\npath = {}\n[outbox]\nmax attempts = 2\nretry delay = 30\nsend timeout = 1\n",
+                dir.join("termplay.sqlite3").display()
+            )
+        ).unwrap();
+        let config = Config::load(&ConfigSource::load(&path, &[]).unwrap(), false).unwrap();
+        let mut conn = create_get_db_connection(&config).unwrap();
+        database::migrations::migrate(&mut conn).unwrap();
+        Arc::new(config)
+    }
+
+    /// Status, attempts and next attempt time of the only message
+    fn row(conn: &Connection) -> (String, u32, u64) {
+        conn.query_row("SELECT status, attempts, next_attempt_at FROM outbox", [], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        }).unwrap()
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_attempt() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let conn = create_get_db_connection(&config).unwrap();
+        let email = Email {
+            to: "alice@example.com".to_string(),
+            subject: "Welcome".to_string(),
+            html: "<p>Welcome</p>".to_string(),
+            text: "Welcome".to_string(),
+        };
+        enqueue(&conn, &email).unwrap();
+
+        // A delivery that never answers counts as failed
+        let before = unix_now();
+        let next = deliver_due(&config, &FailingMailer::Hangs).await.unwrap();
+        let (status, attempts, next_attempt_at) = row(&conn);
+        assert_eq!((status.as_str(), attempts), ("pending", 1));
+        assert!(next_attempt_at >= before + 30 && next_attempt_at <= unix_now() + 30);
+        assert_eq!(next, Some(next_attempt_at));
+
+        // Not due yet
+        deliver_due(&config, &FailingMailer::Fails).await.unwrap();
+        assert_eq!(row(&conn).1, 1);
+
+        conn.execute("UPDATE outbox SET next_attempt_at = 0", []).unwrap();
+        let next = deliver_due(&config, &FailingMailer::Fails).await.unwrap();
+        let (status, attempts, _) = row(&conn);
+        assert_eq!((status.as_str(), attempts), ("dead", 2));
+        assert_eq!(next, None);
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::sync::{ Arc, Mutex };
+
+use database::{ generate_token, unix_now };
+use rusqlite::{ params, Connection, OptionalExtension };
+
+/// Default lifetime of a session, one week
+pub const DEFAULT_SESSION_LIFETIME_SECS: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub token: String,
+    pub login: String,
+    pub expires_at: u64,
+    /// Only kept in memory, a session loaded from the database starts outside
+    pub in_lobby: bool,
+    /// Game being played, only kept in memory like `in_lobby`
+    pub game_id: Option<String>,
+}
+
+/// Open a new session for `login`, valid for `lifetime_secs`
+pub fn create(conn: &Connection, login: &str, lifetime_secs: u64) -> rusqlite::Result<Session> {
+    let now = unix_now();
+    let session = Session {
+        token: generate_token(),
+        login: login.to_string(),
+        expires_at: now + lifetime_secs,
+        in_lobby: false,
+        game_id: None,
+    };
+    conn.execute(
+        "INSERT INTO sessions (token, login, created_at, expires_at) VALUES (?1, ?2, ?3, ?4)",
+        params![session.token, session.login, now as i64, session.expires_at as i64]
+    )?;
+    Ok(session)
+}
+
+/// Look a session up by token, ignoring expired ones
+pub fn find(conn: &Connection, token: &str) -> rusqlite::Result<Option<Session>> {
+    conn.query_row(
+        "SELECT token, login, expires_at FROM sessions WHERE token = ?1 AND expires_at > ?2",
+        params![token, unix_now() as i64],
+        |row| {
+            Ok(Session {
+                token: row.get(0)?,
+                login: row.get(1)?,
+                expires_at: row.get::<_, i64>(2)? as u64,
+                in_lobby: false,
+                game_id: None,
+            })
+        }
+    ).optional()
+}
+
+pub fn delete(conn: &Connection, token: &str) -> rusqlite::Result<()> {
+    conn.execute("DELETE FROM sessions WHERE token = ?1", params![token])?;
+    Ok(())
+}
+
+/// Live sessions shared by every connection, keyed by token.
+///
+/// SQLite stays the source of truth so sessions outlive a server restart,
+/// the registry only saves a database round trip on every resume.
+#[derive(Debug, Clone, Default)]
+pub struct SessionRegistry {
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+}
+
+impl SessionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, session: Session) {
+        self.lock().insert(session.token.clone(), session);
+    }
+
+    pub fn remove(&self, token: &str) {
+        self.lock().remove(token);
+    }
+
+    /// Remember whether the session is in the lobby, so a client reconnecting
+    /// after a network failure finds itself back there
+    pub fn set_in_lobby(&self, token: &str, in_lobby: bool) {
+        if let Some(session) = self.lock().get_mut(token) {
+            session.in_lobby = in_lobby;
+        }
+    }
+
+    /// Remember the game the session is in, so a client reconnecting goes
+    /// back to it
+    pub fn set_game(&self, token: &str, game_id: Option<String>) {
+        if let Some(session) = self.lock().get_mut(token) {
+            session.game_id = game_id;
+        }
+    }
+
+    /// Find the session a reconnecting client asks for, loading it from the
+    /// database when this server has not seen it yet.
+    pub fn resume(&self, conn: &Connection, token: &str) -> rusqlite::Result<Option<Session>> {
+        let now = unix_now();
+        {
+            let mut sessions = self.lock();
+            sessions.retain(|_, session| session.expires_at > now);
+            if let Some(session) = sessions.get(token) {
+                return Ok(Some(session.clone()));
+            }
+        }
+        let session = find(conn, token)?;
+        if let Some(session) = &session {
+            self.insert(session.clone());
+        }
+        Ok(session)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Session>> {
+        // A panic while holding the lock cannot leave the map half updated
+        self.sessions.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::{ Duration, UNIX_EPOCH };
+
+use anyhow::Context;
+use common::html::escape_html;
+
+use crate::mailer::Email;
+
+/// Files of a template set, in the order of [TemplateSet::parts]
+const FILE_NAMES: [&str; 3] = ["confirmation.subject.txt", "confirmation.html", "confirmation.txt"];
+
+/// Templates shipped with the server, which `[mail] templates path` can
+/// replace or add to
+const BUILTIN: &[(&str, [&str; 3])] = &[
+    (
+        "fr",
+        [
+            include_str!("../templates/fr/confirmation.subject.txt"),
+            include_str!("../templates/fr/confirmation.html"),
+            include_str!("../templates/fr/confirmation.txt"),
+        ],
+    ),
+    (
+        "en",
+        [
+            include_str!("../templates/en/confirmation.subject.txt"),
+            include_str!("../templates/en/confirmation.html"),
+            include_str!("../templates/en/confirmation.txt"),
+        ],
+    ),
+];
+
+/// Values a template refers to as `{{name}}`
+pub struct TemplateContext {
+    pub login: String,
+    pub confirm_url: String,
+    /// Unix time after which the link no longer works
+    pub expires_at: u64,
+}
+
+impl TemplateContext {
+    /// Made-up values, to check and preview templates. `confirmation_url`
+    /// is the base URL of the confirmation server.
+    pub fn example(login: &str, confirmation_url: &str) -> Self {
+        Self {
+            login: login.to_string(),
+            confirm_url: format!("{}/confirm/{}", confirmation_url, "0".repeat(64)),
+            expires_at: database::unix_now() + 48 * 60 * 60,
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "login" => Some(self.login.clone()),
+            "confirm_url" => Some(self.confirm_url.clone()),
+            // e.g. Sun, 18 Oct 2026 12:35:38 GMT
+            "expires_at" => Some(httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(self.expires_at))),
+            _ => None,
+        }
+    }
+}
+
+/// Replace every `{{name}}` in `template`, passing the values through
+/// `escape`
+fn substitute(
+    template: &str,
+    context: &TemplateContext,
+    escape: fn(&str) -> String
+) -> anyhow::Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let end = rest[start..].find("}}").with_context(|| {
+            format!("Unclosed '{{{{' in '{}'", rest[start..].lines().next().unwrap_or_default())
+        })?;
+        let name = rest[start + 2..start + end].trim();
+        let value = context.get(name).with_context(|| format!("Unknown variable '{}'", name))?;
+        rendered.push_str(&escape(&value));
+        rest = &rest[start + end + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// Subject, HTML and plain text parts of the confirmation email in one
+/// language
+#[derive(Clone)]
+struct TemplateSet {
+    subject: String,
+    html: String,
+    text: String,
+}
+
+impl TemplateSet {
+    fn parts(&mut self) -> [&mut String; 3] {
+        [&mut self.subject, &mut self.html, &mut self.text]
+    }
+
+    fn render(&self, to: String, context: &TemplateContext) -> anyhow::Result<Email> {
+        Ok(Email {
+            to,
+            // A line break would end the header
+            subject: substitute(self.subject.trim(), context, |value| value.replace(['\r', '\n'], " "))
+                .context(FILE_NAMES[0])?,
+            html: substitute(&self.html, context, escape_html).context(FILE_NAMES[1])?,
+            text: substitute(&self.text, context, str::to_string).context(FILE_NAMES[2])?,
+        })
+    }
+}
+
+/// The confirmation email templates of every locale
+#[derive(Clone)]
+pub struct Templates {
+    sets: HashMap<String, TemplateSet>,
+    default_locale: String,
+}
+
+impl Templates {
+    /// The built-in templates, along with the `<locale>/` directories of
+    /// `path`. Files found there replace the built-in ones, new locales need
+    /// all three.
+    pub fn load(path: Option<&Path>, default_locale: &str) -> anyhow::Result<Self> {
+        let mut sets: HashMap<String, TemplateSet> = BUILTIN.iter()
+            .map(|(locale, [subject, html, text])| {
+                let set = TemplateSet {
+                    subject: subject.to_string(),
+                    html: html.to_string(),
+                    text: text.to_string(),
+                };
+                (locale.to_string(), set)
+            })
+            .collect();
+
+        if let Some(path) = path {
+            let entries = fs
+                ::read_dir(path)
+                .with_context(|| format!("Could not read the templates in '{}'", path.display()))?;
+            for entry in entries {
+                let entry = entry?;
+                if !entry.file_type()?.is_dir() {
+                    continue;
+                }
+                let locale = entry.file_name().to_string_lossy().to_lowercase();
+                let builtin = sets.contains_key(&locale);
+                let set = sets.entry(locale.clone()).or_insert_with(|| TemplateSet {
+                    subject: String::new(),
+                    html: String::new(),
+                    text: String::new(),
+                });
+                for (part, name) in set.parts().into_iter().zip(FILE_NAMES) {
+                    let file = entry.path().join(name);
+                    if !builtin || file.exists() {
+                        *part = fs
+                            ::read_to_string(&file)
+                            .with_context(|| format!("Could not read the template '{}'", file.display()))?;
+                    }
+                }
+            }
+        }
+
+        for (locale, set) in &sets {
+            set.render(String::new(), &TemplateContext::example("alice", "https://example.com")).with_context(||
+                format!("Invalid '{}' email templates", locale)
+            )?;
+        }
+        anyhow::ensure!(
+            sets.contains_key(default_locale),
+            "No email templates for the default locale '{}'",
+            default_locale
+        );
+        Ok(Self {
+            sets,
+            default_locale: default_locale.to_string(),
+        })
+    }
+
+    /// The locale whose templates are used for `requested`: itself, its
+    /// language for a regional variant such as `fr-CA`, or the default one
+    pub fn resolve<'a>(&'a self, requested: Option<&str>) -> &'a str {
+        let requested = requested.map(|locale| locale.to_lowercase().replace('_', "-"));
+        let candidates = requested
+            .iter()
+            .flat_map(|locale| [locale.as_str(), locale.split('-').next().unwrap_or_default()]);
+        for candidate in candidates {
+            if let Some((locale, _)) = self.sets.get_key_value(candidate) {
+                return locale;
+            }
+        }
+        &self.default_locale
+    }
+
+    /// The confirmation email for `to`, in the language closest to `locale`
+    pub fn render(
+        &self,
+        locale: Option<&str>,
+        to: String,
+        context: &TemplateContext
+    ) -> anyhow::Result<Email> {
+        self.sets[self.resolve(locale)].render(to, context)
+    }
+}
+
+impl fmt::Display for Templates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut locales: Vec<&str> = self.sets.keys().map(String::as_str).collect();
+        locales.sort();
+        write!(f, "{} (default {})", locales.join(", "), self.default_locale)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(login: &str) -> TemplateContext {
+        TemplateContext::example(login, "https://play.example.com")
+    }
+
+    #[test]
+    fn substitutes_variables() {
+        let rendered = substitute("Hi {{ login }}, {{confirm_url}}", &context("alice"), str::to_string);
+
+        assert_eq!(
+            rendered.unwrap(),
+            format!("Hi alice, https://play.example.com/confirm/{}", "0".repeat(64))
+        );
+    }
+
+    #[test]
+    fn refuses_an_unknown_variable() {
+        let error = substitute("Hi {{name}}", &context("alice"), str::to_string).unwrap_err();
+
+        assert_eq!(error.to_string(), "Unknown variable 'name'");
+    }
+
+    #[test]
+    fn refuses_an_unclosed_variable() {
+        let error = substitute("Hi {{login\nBye", &context("alice"), str::to_string).unwrap_err();
+
+        assert_eq!(error.to_string(), "Unclosed '{{' in '{{login'");
+    }
+
+    #[test]
+    fn escapes_the_login_in_html() {
+        let rendered = substitute("<p>{{login}}</p>", &context("<b>\"a&b'</b>"), escape_html);
+
+        assert_eq!(rendered.unwrap(), "<p>&lt;b&gt;&quot;a&amp;b&#39;&lt;/b&gt;</p>");
+    }
+
+    #[test]
+    fn falls_back_to_the_language_then_the_default_locale() {
+        let templates = Templates::load(None, "en").unwrap();
+
+        assert_eq!(templates.resolve(Some("fr")), "fr");
+        assert_eq!(templates.resolve(Some("fr-CA")), "fr");
+        assert_eq!(templates.resolve(Some("fr_CA")), "fr");
+        assert_eq!(templates.resolve(Some("de-DE")), "en");
+        assert_eq!(templates.resolve(None), "en");
+    }
+}